@@ -0,0 +1,186 @@
+//! The request layer every YouTube Data API call goes through. It charges
+//! the quota ledger, turns Google error bodies into typed reasons and retries
+//! the transient ones with jittered exponential backoff.
+
+use crate::config::Config;
+use crate::models::{ErrorReason, GoogleErrorResponse};
+use crate::quota::{ApiCall, SharedQuotaLedger};
+use log::warn;
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request never got a response, e.g. a connection failure or timeout.
+    Transport(reqwest::Error),
+    /// Google answered with an error status.
+    Google {
+        status: u16,
+        reason: ErrorReason,
+        message: String,
+    },
+    /// The response body was not what we expected.
+    Parse(reqwest::Error),
+}
+
+impl ApiError {
+    pub fn reason(&self) -> Option<&ErrorReason> {
+        match self {
+            ApiError::Google { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Whether trying the same request again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ApiError::Transport(e) => e.is_timeout() || e.is_connect(),
+            ApiError::Google { reason, .. } => reason.is_transient(),
+            ApiError::Parse(_) => false,
+        }
+    }
+
+    async fn from_response(response: Response) -> ApiError {
+        let status = response.status().as_u16();
+        match response.json::<GoogleErrorResponse>().await {
+            Ok(body) => ApiError::Google {
+                status,
+                reason: body.error.reason(),
+                message: body.error.message().to_string(),
+            },
+            Err(_) => ApiError::Google {
+                status,
+                reason: ErrorReason::from_status(status),
+                message: String::from("no error details in response"),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Transport(e) => write!(f, "request failed: {}", e),
+            ApiError::Google { status, reason, message } => write!(f, "{} {:?}: {}", status, reason, message),
+            ApiError::Parse(e) => write!(f, "unexpected response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with "equal jitter": half of the delay is fixed and
+    /// the other half random, so parallel clients do not retry in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self.base_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let capped = exponential.min(self.max_delay);
+        let half = capped / 2;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or(0);
+        let jitter = half.mul_f64(nanos as f64 / 1_000_000_000.0);
+        half + jitter
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    client: Client,
+    base_url: String,
+    quota: SharedQuotaLedger,
+    retry: RetryPolicy,
+}
+
+impl ApiClient {
+    pub fn new(config: &Config, quota: SharedQuotaLedger) -> Self {
+        ApiClient {
+            client: Client::new(),
+            base_url: config.api_base_url(),
+            quota,
+            retry: config.retry_policy(),
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn quota(&self) -> SharedQuotaLedger {
+        self.quota.clone()
+    }
+
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    /// Sends the request built by `build`, rebuilding it for every retry.
+    /// Each attempt is charged to the quota ledger, since Google charges for
+    /// failed requests too.
+    pub async fn execute<F>(&self, call: ApiCall, build: F) -> Result<Response, ApiError>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 1;
+        loop {
+            self.quota.lock().unwrap().record(call);
+            let error = match build().send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => ApiError::from_response(response).await,
+                Err(e) => ApiError::Transport(e),
+            };
+
+            if error.reason() == Some(&ErrorReason::QuotaExceeded) {
+                self.quota.lock().unwrap().exhaust();
+            }
+            if !error.is_transient() || attempt >= self.retry.max_attempts {
+                return Err(error);
+            }
+
+            let delay = self.retry.delay(attempt);
+            warn!(
+                "{} attempt {} of {} failed ({}), retrying in {:?}",
+                call.name(),
+                attempt,
+                self.retry.max_attempts,
+                error,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Like `execute`, parsing the response body as JSON.
+    pub async fn json<T, F>(&self, call: ApiCall, build: F) -> Result<T, ApiError>
+    where
+        T: DeserializeOwned,
+        F: Fn() -> RequestBuilder,
+    {
+        let response = self.execute(call, build).await?;
+        response.json::<T>().await.map_err(ApiError::Parse)
+    }
+}
+
+#[test]
+fn test_retry_policy_delay() {
+    let policy = RetryPolicy {
+        max_attempts: 5,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(500),
+    };
+    for (attempt, full) in [(1, 100), (2, 200), (3, 400), (4, 500), (10, 500)] {
+        let delay = policy.delay(attempt);
+        assert!(delay >= Duration::from_millis(full / 2), "attempt {}: {:?}", attempt, delay);
+        assert!(delay <= Duration::from_millis(full), "attempt {}: {:?}", attempt, delay);
+    }
+}
@@ -0,0 +1,184 @@
+//! OAuth for the YouTube Data API.
+//!
+//! Authorizing is interactive and only happens in `sonotube auth login`, which
+//! writes the token cache. Everything else reads tokens from that cache and
+//! fails instead of prompting, so the monitor never waits on a browser.
+
+use crate::tube::TubeError;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use yup_oauth2::authenticator::DefaultAuthenticator;
+use yup_oauth2::authenticator_delegate::InstalledFlowDelegate;
+use yup_oauth2::{
+    AccessToken, ApplicationSecret, DeviceFlowAuthenticator, InstalledFlowAuthenticator,
+    InstalledFlowReturnMethod,
+};
+
+pub const LOGIN_COMMAND: &str = "sonotube auth login";
+
+/// How `sonotube auth login` asks the user for access.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OAuthFlow {
+    /// Opens a local redirect listener for a browser on the same machine.
+    #[default]
+    Browser,
+    /// Prints a verification URL and code to enter on any other device, then
+    /// polls until access is granted. For machines without a browser.
+    DeviceCode,
+}
+
+impl OAuthFlow {
+    pub fn parse(value: &str) -> Option<OAuthFlow> {
+        match value.trim().to_lowercase().as_str() {
+            "browser" => Some(OAuthFlow::Browser),
+            "devicecode" | "device-code" | "device" => Some(OAuthFlow::DeviceCode),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OAuthSettings {
+    pub client_secrets_path: PathBuf,
+    pub token_cache_path: PathBuf,
+    pub scopes: Vec<String>,
+    pub flow: OAuthFlow,
+}
+
+impl OAuthSettings {
+    async fn secret(&self) -> Result<ApplicationSecret, TubeError> {
+        let secrets_path = &self.client_secrets_path;
+        yup_oauth2::read_application_secret(secrets_path)
+            .await
+            .map_err(|e| TubeError::Auth(format!("unable to read {}: {}", secrets_path.display(), e)))
+    }
+}
+
+/// Refuses to start an interactive authorization.
+struct NoPrompt;
+
+impl InstalledFlowDelegate for NoPrompt {
+    fn present_user_url<'a>(
+        &'a self,
+        _url: &'a str,
+        _need_code: bool,
+    ) -> Pin<Box<dyn Future<Output = Result<String, String>> + Send + 'a>> {
+        Box::pin(async {
+            Err(format!("no usable token in the token cache. Run `{}` first", LOGIN_COMMAND))
+        })
+    }
+}
+
+/// Runs the configured flow and writes the token to the token cache.
+pub async fn login(settings: &OAuthSettings) -> Result<AccessToken, TubeError> {
+    let secret = settings.secret().await?;
+    let token = match settings.flow {
+        OAuthFlow::Browser => {
+            InstalledFlowAuthenticator::builder(secret, InstalledFlowReturnMethod::HTTPRedirect)
+                .persist_tokens_to_disk(settings.token_cache_path.clone())
+                .build()
+                .await
+                .map_err(build_error)?
+                .token(&settings.scopes)
+                .await
+        }
+        OAuthFlow::DeviceCode => {
+            // The default delegate prints the verification URL and the code.
+            DeviceFlowAuthenticator::builder(secret)
+                .persist_tokens_to_disk(settings.token_cache_path.clone())
+                .build()
+                .await
+                .map_err(build_error)?
+                .token(&settings.scopes)
+                .await
+        }
+    };
+    token.map_err(|e| TubeError::Auth(e.to_string()))
+}
+
+/// Hands out access tokens from the token cache, refreshing them as they
+/// expire. Never prompts.
+#[derive(Clone)]
+pub struct TokenSource {
+    auth: DefaultAuthenticator,
+    scopes: Vec<String>,
+}
+
+impl std::fmt::Debug for TokenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenSource").field("scopes", &self.scopes).finish()
+    }
+}
+
+impl TokenSource {
+    pub async fn new(settings: &OAuthSettings) -> Result<TokenSource, TubeError> {
+        let secret = settings.secret().await?;
+        // Only the interactive return method gives up when the delegate refuses;
+        // the redirect method would wait for a browser regardless.
+        let auth = InstalledFlowAuthenticator::builder(secret, InstalledFlowReturnMethod::Interactive)
+            .persist_tokens_to_disk(settings.token_cache_path.clone())
+            .flow_delegate(Box::new(NoPrompt))
+            .build()
+            .await
+            .map_err(build_error)?;
+        Ok(TokenSource {
+            auth,
+            scopes: settings.scopes.clone(),
+        })
+    }
+
+    /// The cached token, or a refreshed one if it has expired.
+    pub async fn token(&self) -> Result<String, TubeError> {
+        let token = self.auth.token(&self.scopes).await.map_err(token_error)?;
+        Ok(token.as_str().to_string())
+    }
+
+    /// A new token, for when Google rejected one that had not expired yet.
+    pub async fn refreshed_token(&self) -> Result<String, TubeError> {
+        let token = self.auth.force_refreshed_token(&self.scopes).await.map_err(token_error)?;
+        Ok(token.as_str().to_string())
+    }
+}
+
+fn token_error(err: yup_oauth2::Error) -> TubeError {
+    error!("Failed to obtain access token: {:?}", err);
+    TubeError::Auth(err.to_string())
+}
+
+fn build_error(e: std::io::Error) -> TubeError {
+    TubeError::Auth(format!("unable to build authenticator: {}", e))
+}
+
+#[tokio::test]
+async fn test_token_source_never_prompts() {
+    let dir = std::env::temp_dir();
+    let secrets = dir.join("sonotube_test_installed_secrets.json");
+    std::fs::write(
+        &secrets,
+        r#"{"installed":{"client_id":"id","client_secret":"secret",
+            "auth_uri":"http://127.0.0.1:9/auth","token_uri":"http://127.0.0.1:9/token",
+            "redirect_uris":["http://localhost"]}}"#,
+    )
+    .unwrap();
+    let token_cache = dir.join("sonotube_test_empty_token_cache.json");
+    let _ = std::fs::remove_file(&token_cache);
+
+    let settings = OAuthSettings {
+        client_secrets_path: secrets,
+        token_cache_path: token_cache,
+        scopes: vec![String::from("scope.a")],
+        flow: OAuthFlow::DeviceCode,
+    };
+    match TokenSource::new(&settings).await.unwrap().token().await {
+        Err(TubeError::Auth(message)) => assert!(message.contains(LOGIN_COMMAND), "{}", message),
+        other => panic!("expected an auth error, got {:?}", other),
+    }
+
+    assert_eq!(OAuthFlow::parse("deviceCode"), Some(OAuthFlow::DeviceCode));
+    assert_eq!(OAuthFlow::parse("Browser"), Some(OAuthFlow::Browser));
+    assert_eq!(OAuthFlow::parse("fax"), None);
+}
@@ -1,22 +1,111 @@
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use crate::tube;
+use crate::api::RetryPolicy;
+use crate::auth::{OAuthFlow, OAuthSettings};
+use crate::models::{PlaylistPrivacy, SearchFilters};
+use crate::play_rules::PlayRules;
+use crate::template::{DEFAULT_DESCRIPTION_TEMPLATE, DEFAULT_TITLE_TEMPLATE};
+use crate::tube::{self, PlaylistTarget};
+use crate::video_filter::{VideoFilter, VideoFilterConfig};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
 use std::{fs::OpenOptions, path::PathBuf};
 
 const CONFIG: &str = ".sonotube.json";
+pub const CLIENT_SECRETS_VAR: &str = "SONOTUBE_CLIENT_SECRETS";
+pub const TOKEN_CACHE_VAR: &str = "SONOTUBE_TOKEN_CACHE";
+pub const OAUTH_SCOPES_VAR: &str = "SONOTUBE_OAUTH_SCOPES";
+pub const OAUTH_FLOW_VAR: &str = "SONOTUBE_OAUTH_FLOW";
+const DEFAULT_CLIENT_SECRETS_FILE: &str = "client_secrets.json";
+const DEFAULT_TOKEN_CACHE_FILE: &str = "sonotube_token_cache.json";
+/// The account made of the top-level settings.
+pub const DEFAULT_ACCOUNT: &str = "default";
+const DEFAULT_OAUTH_SCOPE: &str = "https://www.googleapis.com/auth/youtube";
+const DEFAULT_API_BASE_URL: &str = "https://www.googleapis.com/youtube/v3";
+const DEFAULT_MIN_MATCH_CONFIDENCE: f64 = 0.5;
+const DEFAULT_SEARCH_MAX_RESULTS: u64 = 10;
+const DEFAULT_SEARCH_VARIANTS: usize = 3;
+const DEFAULT_DURATION_TOLERANCE_SECS: u64 = 20;
+const DEFAULT_MATCH_CACHE_TTL_DAYS: u64 = 90;
+const DEFAULT_QUOTA_BUDGET: u64 = 10_000;
+const DEFAULT_QUOTA_RESERVE: u64 = 1_000;
+const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 4;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 500;
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+const DEFAULT_SONOS_POLL_INTERVAL_SECS: u64 = 30;
+const DEFAULT_SONOS_DISCOVERY_INTERVAL_SECS: u64 = 300;
+// Last.fm's scrobbling rules: tracks of 30 seconds or more, played for half their
+// length or 4 minutes, whichever comes first.
+const DEFAULT_PLAY_MIN_LISTENED_SECS: u64 = 30;
+const DEFAULT_PLAY_MIN_TRACK_SECS: u64 = 30;
+const DEFAULT_PLAY_MIN_PERCENT: f64 = 50.0;
+const DEFAULT_PLAY_ENOUGH_SECS: u64 = 240;
+/// YouTube does not allow more items than this in one playlist.
+const MAX_PLAYLIST_ITEMS: u32 = 5_000;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     api_key: Option<String>,
     create_sonotube_playlist: Option<bool>,
     send_previous_tracks: Option<bool>,
     create_toptastic_playlist: Option<bool>,
+    api_base_url: Option<String>,
+    access_token: Option<String>,
+    min_match_confidence: Option<f64>,
+    search_max_results: Option<u64>,
+    search_variants: Option<usize>,
+    duration_tolerance_secs: Option<u64>,
+    match_cache_ttl_days: Option<u64>,
+    quota_budget: Option<u64>,
+    quota_reserve: Option<u64>,
+    retry_max_attempts: Option<u32>,
+    retry_base_delay_ms: Option<u64>,
+    playlist_id: Option<String>,
+    playlist_title: Option<String>,
+    playlist_item_limit: Option<u32>,
+    playlist_privacy: Option<PlaylistPrivacy>,
+    playlist_title_template: Option<String>,
+    playlist_description_template: Option<String>,
+    client_secrets_path: Option<String>,
+    token_cache_path: Option<String>,
+    oauth_scopes: Option<Vec<String>>,
+    oauth_flow: Option<OAuthFlow>,
+    accounts: Option<HashMap<String, AccountConfig>>,
+    search_filters: Option<SearchFilters>,
+    video_filter: Option<VideoFilterConfig>,
+    sonos_events: Option<bool>,
+    sonos_event_port: Option<u16>,
+    sonos_poll_interval_secs: Option<u64>,
+    sonos_discovery_interval_secs: Option<u64>,
+    /// Speakers to ask directly, for networks where SSDP does not reach them.
+    sonos_speaker_ips: Option<Vec<String>>,
+    play_min_listened_secs: Option<u64>,
+    play_min_track_secs: Option<u64>,
+    play_min_percent: Option<f64>,
+    play_enough_secs: Option<u64>,
+}
+
+/// A Google account playlists can go to, besides the default one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountConfig {
+    /// Falls back to the top-level client secrets.
+    client_secrets_path: Option<String>,
+    /// Defaults to a token cache of its own in the cache directory.
+    token_cache_path: Option<String>,
+    access_token: Option<String>,
+    playlist_id: Option<String>,
+    /// Falls back to the top-level playlist title.
+    playlist_title: Option<String>,
+    /// Sonos rooms whose plays go to this account.
+    rooms: Option<Vec<String>>,
 }
 
 impl Config {
     pub fn new() -> Self {
-        let config = match Config::load(CONFIG) {
+        let mut config = match Config::load(CONFIG) {
             Some(config) => {
                 info!("Loaded config: {:?}", &config);
                 if let Some(value) = &config.api_key {
@@ -26,17 +115,303 @@ impl Config {
             }
             None => {
                 info!("No config found. Using defaults");
-                Config {
-                    api_key: None,
-                    create_sonotube_playlist: None,
-                    send_previous_tracks: None,
-                    create_toptastic_playlist: None,
-                }
+                Config::default()
             }
         };
+        config.apply_env_overrides(|name| std::env::var(name).ok());
         config
     }
 
+    /// Lets environment variables override the OAuth settings from the file.
+    fn apply_env_overrides<F: Fn(&str) -> Option<String>>(&mut self, var: F) {
+        if let Some(path) = var(CLIENT_SECRETS_VAR) {
+            self.client_secrets_path = Some(path);
+        }
+        if let Some(path) = var(TOKEN_CACHE_VAR) {
+            self.token_cache_path = Some(path);
+        }
+        if let Some(scopes) = var(OAUTH_SCOPES_VAR) {
+            let scopes = scopes
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|scope| !scope.is_empty())
+                .map(String::from)
+                .collect();
+            self.oauth_scopes = Some(scopes);
+        }
+        if let Some(flow) = var(OAUTH_FLOW_VAR) {
+            match OAuthFlow::parse(&flow) {
+                Some(flow) => self.oauth_flow = Some(flow),
+                None => warn!("Ignoring unknown {} {:?}", OAUTH_FLOW_VAR, flow),
+            }
+        }
+    }
+
+    /// The default account followed by the configured ones, by name.
+    pub fn account_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .accounts
+            .iter()
+            .flat_map(|accounts| accounts.keys().cloned())
+            .filter(|name| name != DEFAULT_ACCOUNT)
+            .collect();
+        names.sort();
+        names.insert(0, String::from(DEFAULT_ACCOUNT));
+        names
+    }
+
+    /// This config with the credentials and playlist of the named account,
+    /// or `None` if there is no such account.
+    pub fn for_account(&self, name: &str) -> Option<Config> {
+        if name == DEFAULT_ACCOUNT {
+            return Some(self.clone());
+        }
+        let account = self.accounts.as_ref()?.get(name)?.clone();
+        let token_cache_path = account.token_cache_path.unwrap_or_else(|| {
+            let mut path = dirs::cache_dir().unwrap_or_default();
+            path.push(format!("sonotube_token_cache_{}.json", name));
+            path.to_string_lossy().to_string()
+        });
+        Some(Config {
+            client_secrets_path: account.client_secrets_path.or_else(|| self.client_secrets_path.clone()),
+            token_cache_path: Some(token_cache_path),
+            access_token: account.access_token,
+            playlist_id: account.playlist_id,
+            playlist_title: account.playlist_title.or_else(|| self.playlist_title.clone()),
+            accounts: None,
+            ..self.clone()
+        })
+    }
+
+    /// The account the plays in a Sonos room go to.
+    pub fn account_for_room(&self, room: &str) -> String {
+        self.accounts
+            .iter()
+            .flatten()
+            .find(|(_, account)| {
+                account
+                    .rooms
+                    .iter()
+                    .flatten()
+                    .any(|account_room| account_room.eq_ignore_ascii_case(room))
+            })
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| String::from(DEFAULT_ACCOUNT))
+    }
+
+    /// Config pointing at a local fake YouTube Data API with a fixed bearer token.
+    #[cfg(test)]
+    pub fn for_fake_tube(api_base_url: &str) -> Self {
+        Config {
+            api_key: Some(String::from("fake-api-key")),
+            create_sonotube_playlist: Some(true),
+            send_previous_tracks: Some(false),
+            create_toptastic_playlist: Some(true),
+            api_base_url: Some(String::from(api_base_url)),
+            access_token: Some(String::from("fake-access-token")),
+            retry_base_delay_ms: Some(1),
+            ..Default::default()
+        }
+    }
+
+    /// Base URL of the YouTube Data API, without a trailing slash.
+    pub fn api_base_url(&self) -> String {
+        match &self.api_base_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => String::from(DEFAULT_API_BASE_URL),
+        }
+    }
+
+    /// A pre-issued bearer token. When set, the OAuth flow is skipped.
+    pub fn access_token(&self) -> Option<String> {
+        self.access_token.clone()
+    }
+
+    pub fn api_key(&self) -> Option<String> {
+        self.api_key.clone()
+    }
+
+    /// Search results scoring below this are treated as no match at all.
+    pub fn min_match_confidence(&self) -> f64 {
+        self.min_match_confidence.unwrap_or(DEFAULT_MIN_MATCH_CONFIDENCE)
+    }
+
+    /// How many search results to rank for each track.
+    pub fn search_max_results(&self) -> u64 {
+        self.search_max_results.unwrap_or(DEFAULT_SEARCH_MAX_RESULTS)
+    }
+
+    /// How many query variants a track may be searched with before giving
+    /// up. Each search costs 100 units of quota.
+    pub fn search_variants(&self) -> usize {
+        self.search_variants.unwrap_or(DEFAULT_SEARCH_VARIANTS).max(1)
+    }
+
+    /// Search parameters applied to every search, unless a request overrides them.
+    pub fn search_filters(&self) -> SearchFilters {
+        self.search_filters.clone().unwrap_or_default()
+    }
+
+    /// The allow and deny lists for matched videos, or why they cannot be used.
+    pub fn video_filter(&self) -> Result<VideoFilter, String> {
+        VideoFilter::new(&self.video_filter.clone().unwrap_or_default())
+    }
+
+    /// How far a video's length may be from the track's and still count as the same recording.
+    pub fn duration_tolerance(&self) -> Duration {
+        Duration::from_secs(self.duration_tolerance_secs.unwrap_or(DEFAULT_DURATION_TOLERANCE_SECS))
+    }
+
+    /// How long a cached search match is trusted before searching again.
+    pub fn match_cache_ttl(&self) -> Duration {
+        let days = self.match_cache_ttl_days.unwrap_or(DEFAULT_MATCH_CACHE_TTL_DAYS);
+        Duration::from_secs(days * 24 * 60 * 60)
+    }
+
+    /// YouTube Data API units we allow ourselves to spend per day.
+    pub fn quota_budget(&self) -> u64 {
+        self.quota_budget.unwrap_or(DEFAULT_QUOTA_BUDGET)
+    }
+
+    /// Units of the daily budget kept back for urgent work such as toptastic requests.
+    pub fn quota_reserve(&self) -> u64 {
+        self.quota_reserve.unwrap_or(DEFAULT_QUOTA_RESERVE)
+    }
+
+    /// How transient YouTube API errors are retried.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry_max_attempts.unwrap_or(DEFAULT_RETRY_MAX_ATTEMPTS).max(1),
+            base_delay: Duration::from_millis(self.retry_base_delay_ms.unwrap_or(DEFAULT_RETRY_BASE_DELAY_MS)),
+            max_delay: RETRY_MAX_DELAY,
+        }
+    }
+
+    /// How long a track has to be listened to before it counts as played.
+    pub fn play_rules(&self) -> PlayRules {
+        let percent = self.play_min_percent.unwrap_or(DEFAULT_PLAY_MIN_PERCENT).clamp(0.0, 100.0);
+        PlayRules {
+            min_listened: Duration::from_secs(self.play_min_listened_secs.unwrap_or(DEFAULT_PLAY_MIN_LISTENED_SECS)),
+            min_track_length: Duration::from_secs(self.play_min_track_secs.unwrap_or(DEFAULT_PLAY_MIN_TRACK_SECS)),
+            share: percent / 100.0,
+            enough: Duration::from_secs(self.play_enough_secs.unwrap_or(DEFAULT_PLAY_ENOUGH_SECS)),
+        }
+    }
+
+    /// The playlist sonotube adds tracks to. A configured id wins over a
+    /// configured title; with neither, every run creates a new playlist.
+    pub fn playlist_target(&self) -> PlaylistTarget {
+        match (&self.playlist_id, &self.playlist_title) {
+            (Some(id), _) => PlaylistTarget::Id(id.clone()),
+            (None, Some(title)) => PlaylistTarget::Title(title.clone()),
+            (None, None) => PlaylistTarget::New,
+        }
+    }
+
+    /// How many items a playlist may hold before sonotube continues in a new part.
+    pub fn playlist_item_limit(&self) -> u32 {
+        self.playlist_item_limit.unwrap_or(MAX_PLAYLIST_ITEMS).min(MAX_PLAYLIST_ITEMS)
+    }
+
+    pub fn playlist_privacy(&self) -> PlaylistPrivacy {
+        self.playlist_privacy.unwrap_or_default()
+    }
+
+    /// Template for the titles of new playlists, see `template::render`.
+    pub fn playlist_title_template(&self) -> String {
+        self.playlist_title_template
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_TITLE_TEMPLATE))
+    }
+
+    /// Template for the descriptions of new playlists, see `template::render`.
+    pub fn playlist_description_template(&self) -> String {
+        self.playlist_description_template
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_DESCRIPTION_TEMPLATE))
+    }
+
+    /// The OAuth client secrets downloaded from the Google Cloud console.
+    /// Defaults to `client_secrets.json` in the `sonotube` config directory.
+    pub fn client_secrets_path(&self) -> PathBuf {
+        match &self.client_secrets_path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let mut path = dirs::config_dir().unwrap_or_default();
+                path.push("sonotube");
+                path.push(DEFAULT_CLIENT_SECRETS_FILE);
+                path
+            }
+        }
+    }
+
+    /// Where OAuth tokens are kept between runs. Defaults to the cache directory.
+    pub fn token_cache_path(&self) -> PathBuf {
+        match &self.token_cache_path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let mut path = dirs::cache_dir().unwrap_or_default();
+                path.push(DEFAULT_TOKEN_CACHE_FILE);
+                path
+            }
+        }
+    }
+
+    pub fn oauth_scopes(&self) -> Vec<String> {
+        match &self.oauth_scopes {
+            Some(scopes) => scopes.clone(),
+            None => vec![String::from(DEFAULT_OAUTH_SCOPE)],
+        }
+    }
+
+    pub fn oauth_flow(&self) -> OAuthFlow {
+        self.oauth_flow.unwrap_or_default()
+    }
+
+    pub fn oauth_settings(&self) -> OAuthSettings {
+        OAuthSettings {
+            client_secrets_path: self.client_secrets_path(),
+            token_cache_path: self.token_cache_path(),
+            scopes: self.oauth_scopes(),
+            flow: self.oauth_flow(),
+        }
+    }
+
+    /// Checks the OAuth settings, unless a pre-issued access token makes them unnecessary.
+    pub fn validate_oauth(&self) -> Result<(), String> {
+        if self.access_token.is_some() {
+            return Ok(());
+        }
+
+        let mut problems = Vec::new();
+        let secrets_path = self.client_secrets_path();
+        if !secrets_path.is_file() {
+            problems.push(format!(
+                "client secrets not found at {:?}. Set clientSecretsPath in {} or {}",
+                secrets_path, CONFIG, CLIENT_SECRETS_VAR
+            ));
+        }
+        let token_cache_path = self.token_cache_path();
+        match token_cache_path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() || dir.is_dir() => {}
+            _ => problems.push(format!(
+                "the directory for the token cache {:?} does not exist. Set tokenCachePath in {} or {}",
+                token_cache_path, CONFIG, TOKEN_CACHE_VAR
+            )),
+        }
+        if self.oauth_scopes().is_empty() {
+            problems.push(format!(
+                "no OAuth scopes. Set oauthScopes in {} or {}",
+                CONFIG, OAUTH_SCOPES_VAR
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
+    }
+
     pub fn create_sonotube_play_list(&self) -> bool {
         match self.create_sonotube_playlist {
             Some(val) => val,
@@ -58,6 +433,44 @@ impl Config {
         }
     }
 
+    /// Whether speakers report track changes through UPnP events. Speakers
+    /// that cannot be subscribed to are polled either way.
+    pub fn sonos_events(&self) -> bool {
+        self.sonos_events.unwrap_or(true)
+    }
+
+    /// The port speakers send events to. Any free port when 0 or left out.
+    pub fn sonos_event_port(&self) -> u16 {
+        self.sonos_event_port.unwrap_or(0)
+    }
+
+    /// How often speakers without an event subscription are asked for their track.
+    pub fn sonos_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.sonos_poll_interval_secs.unwrap_or(DEFAULT_SONOS_POLL_INTERVAL_SECS).max(1))
+    }
+
+    /// How often to look for speakers that were added, unplugged or moved.
+    pub fn sonos_discovery_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.sonos_discovery_interval_secs
+                .unwrap_or(DEFAULT_SONOS_DISCOVERY_INTERVAL_SECS)
+                .max(1),
+        )
+    }
+
+    /// The speakers to ask directly besides discovering them, or why they cannot be used.
+    pub fn sonos_speaker_ips(&self) -> Result<Vec<IpAddr>, String> {
+        self.sonos_speaker_ips
+            .iter()
+            .flatten()
+            .map(|ip| {
+                ip.trim()
+                    .parse()
+                    .map_err(|_| format!("{:?} is not an IP address", ip))
+            })
+            .collect()
+    }
+
     fn load(file_name: &str) -> Option<Self> {
         use std::fs;
         let config_path = Config::get_config_path(file_name);
@@ -92,3 +505,81 @@ impl Config {
     }
 }
 
+#[test]
+fn test_oauth_settings() {
+    let mut config = Config::default();
+    assert_eq!(config.oauth_scopes(), vec![DEFAULT_OAUTH_SCOPE]);
+    assert_eq!(config.oauth_flow(), OAuthFlow::Browser);
+    assert!(config.token_cache_path().ends_with(DEFAULT_TOKEN_CACHE_FILE));
+
+    let secrets = std::env::temp_dir().join("sonotube_test_client_secrets.json");
+    std::fs::write(&secrets, "{}").unwrap();
+    config.client_secrets_path = Some(String::from("/no/such/client_secrets.json"));
+    config.apply_env_overrides(|name| match name {
+        CLIENT_SECRETS_VAR => Some(secrets.to_string_lossy().to_string()),
+        OAUTH_SCOPES_VAR => Some(String::from("scope.a, scope.b")),
+        OAUTH_FLOW_VAR => Some(String::from("deviceCode")),
+        _ => None,
+    });
+    assert_eq!(config.oauth_flow(), OAuthFlow::DeviceCode);
+    assert_eq!(config.client_secrets_path(), secrets);
+    assert_eq!(config.oauth_scopes(), vec!["scope.a", "scope.b"]);
+    assert!(config.validate_oauth().is_ok());
+
+    config.token_cache_path = Some(String::from("/no/such/dir/token_cache.json"));
+    config.oauth_scopes = Some(Vec::new());
+    let problems = config.validate_oauth().unwrap_err();
+    assert!(problems.contains(TOKEN_CACHE_VAR));
+    assert!(problems.contains(OAUTH_SCOPES_VAR));
+
+    config.access_token = Some(String::from("token"));
+    assert!(config.validate_oauth().is_ok());
+}
+
+#[test]
+fn test_accounts() {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "playlistId": "PLshared",
+        "playlistTitle": "sonotube",
+        "accessToken": "default-token",
+        "accounts": {
+            "alice": { "tokenCachePath": "/tmp/alice.json", "rooms": ["Kitchen", "Office"] },
+            "bob": { "playlistId": "PLbob", "rooms": ["bedroom"] },
+        },
+    }))
+    .unwrap();
+
+    assert_eq!(config.account_names(), vec![DEFAULT_ACCOUNT, "alice", "bob"]);
+    assert_eq!(config.account_for_room("office"), "alice");
+    assert_eq!(config.account_for_room("Bedroom"), "bob");
+    assert_eq!(config.account_for_room("Living Room"), DEFAULT_ACCOUNT);
+    assert!(config.for_account("carol").is_none());
+
+    let alice = config.for_account("alice").unwrap();
+    assert_eq!(alice.token_cache_path(), PathBuf::from("/tmp/alice.json"));
+    assert_eq!(alice.access_token(), None);
+    assert_eq!(alice.playlist_target(), PlaylistTarget::Title(String::from("sonotube")));
+    assert_eq!(alice.account_names(), vec![DEFAULT_ACCOUNT]);
+
+    let bob = config.for_account("bob").unwrap();
+    assert!(bob.token_cache_path().ends_with("sonotube_token_cache_bob.json"));
+    assert_eq!(bob.playlist_target(), PlaylistTarget::Id(String::from("PLbob")));
+
+    let default = config.for_account(DEFAULT_ACCOUNT).unwrap();
+    assert_eq!(default.access_token().as_deref(), Some("default-token"));
+    assert_eq!(default.playlist_target(), PlaylistTarget::Id(String::from("PLshared")));
+}
+
+#[test]
+fn test_sonos_discovery_settings() {
+    let mut config = Config::default();
+    assert_eq!(config.sonos_discovery_interval(), Duration::from_secs(DEFAULT_SONOS_DISCOVERY_INTERVAL_SECS));
+    assert_eq!(config.sonos_speaker_ips(), Ok(Vec::new()));
+
+    config.sonos_speaker_ips = Some(vec![String::from("10.0.1.20"), String::from(" fe80::1 ")]);
+    let ips = config.sonos_speaker_ips().unwrap();
+    assert_eq!(ips, vec!["10.0.1.20".parse::<IpAddr>().unwrap(), "fe80::1".parse().unwrap()]);
+
+    config.sonos_speaker_ips = Some(vec![String::from("kitchen.local")]);
+    assert!(config.sonos_speaker_ips().unwrap_err().contains("kitchen.local"));
+}
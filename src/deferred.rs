@@ -0,0 +1,81 @@
+//! Tracks put off until the YouTube quota resets, kept in the cache
+//! directory next to the quota ledger so a restart does not lose them.
+
+use crate::json_file;
+use crate::models::TubeTrack;
+#[cfg(test)]
+use crate::models::test_track;
+use log::{info, warn};
+
+pub const DEFERRED: &str = ".sonotube_deferred.json";
+
+#[derive(Debug, Clone, Default)]
+pub struct DeferredTracks {
+    file_name: Option<String>,
+    tracks: Vec<TubeTrack>,
+}
+
+impl DeferredTracks {
+    pub fn load(file_name: &str) -> Self {
+        let tracks: Vec<TubeTrack> =
+            json_file::load(&json_file::cache_path(file_name), "deferred tracks").unwrap_or_default();
+        if !tracks.is_empty() {
+            info!("Loaded {} tracks deferred for lack of quota", tracks.len());
+        }
+        DeferredTracks {
+            file_name: Some(file_name.to_string()),
+            tracks,
+        }
+    }
+
+    /// Deferred tracks that are never written to disk.
+    pub fn in_memory() -> Self {
+        DeferredTracks::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// Defers a track, once however often it comes along.
+    pub fn push(&mut self, track: &TubeTrack) {
+        if !self.tracks.iter().any(|deferred| deferred.id == track.id) {
+            self.tracks.push(track.clone());
+            self.save();
+        }
+    }
+
+    /// Takes all deferred tracks, to retry them or give up on them.
+    pub fn take(&mut self) -> Vec<TubeTrack> {
+        let tracks = std::mem::take(&mut self.tracks);
+        if !tracks.is_empty() {
+            self.save();
+        }
+        tracks
+    }
+
+    fn save(&self) {
+        let file_name = match &self.file_name {
+            Some(file_name) => file_name,
+            None => return,
+        };
+        if let Err(e) = json_file::save(&json_file::cache_path(file_name), &self.tracks) {
+            warn!("Unable to save the deferred tracks: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_load_save_deferred_tracks() {
+    let test_file_name = ".test_deferred_tracks.json";
+    let mut deferred = DeferredTracks::load(test_file_name);
+    deferred.take();
+    deferred.push(&test_track("1", "Houdini", "Dua Lipa"));
+    deferred.push(&test_track("1", "Houdini", "Dua Lipa"));
+    deferred.push(&test_track("2", "Houdini", "Dua Lipa"));
+
+    let mut loaded = DeferredTracks::load(test_file_name);
+    let ids: Vec<String> = loaded.take().into_iter().map(|track| track.id).collect();
+    assert_eq!(ids, vec!["1", "2"]);
+    assert!(DeferredTracks::load(test_file_name).is_empty());
+}
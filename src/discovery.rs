@@ -0,0 +1,212 @@
+//! Finds Sonos speakers, again and again, so speakers that are added,
+//! unplugged or given a new address are noticed while sonotube runs.
+//!
+//! SSDP multicast does not cross VLANs on many networks, so speakers can also
+//! be listed by IP in the config. `DeviceRegistry` turns each round of
+//! discovery into the speakers that appeared and disappeared since the last.
+
+use log::{info, warn};
+use sonos::Speaker;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// A speaker missing from this many discoveries in a row is taken as gone.
+/// SSDP answers get lost now and then.
+const MISSED_DISCOVERIES: u32 = 2;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// Discovers speakers through SSDP and asks each static IP directly. Only
+/// fails if neither found a thing to go on.
+pub async fn discover(static_ips: &[IpAddr]) -> Result<Vec<Speaker>, String> {
+    let mut speakers = Vec::new();
+    let mut problems = Vec::new();
+    match sonos::discover().await {
+        Ok(found) => speakers.extend(found),
+        Err(e) => problems.push(format!("SSDP discovery failed: {}", e)),
+    }
+    for ip in static_ips {
+        match Speaker::from_ip(*ip).await {
+            Ok(speaker) => speakers.push(speaker),
+            Err(e) => warn!("No Sonos speaker answered at {}: {}", ip, e),
+        }
+    }
+    if speakers.is_empty() && !problems.is_empty() {
+        return Err(problems.join("; "));
+    }
+    for problem in problems {
+        warn!("{}", problem);
+    }
+    Ok(speakers)
+}
+
+/// When to discover next: on an interval, and sooner with a growing delay
+/// after discovery failed.
+#[derive(Debug)]
+pub struct DiscoverySchedule {
+    interval: Duration,
+    failures: u32,
+    next_at: Instant,
+}
+
+impl DiscoverySchedule {
+    /// Due right away.
+    pub fn new(interval: Duration) -> Self {
+        DiscoverySchedule {
+            interval,
+            failures: 0,
+            next_at: Instant::now(),
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next_at
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.next_at = Instant::now() + self.interval;
+    }
+
+    /// Schedules a retry and returns how long until it.
+    pub fn failed(&mut self) -> Duration {
+        let delay = RETRY_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.interval.max(RETRY_BASE_DELAY));
+        self.failures += 1;
+        self.next_at = Instant::now() + delay;
+        delay
+    }
+}
+
+/// Speakers that showed up or went away in one round of discovery. A speaker
+/// with a new address is in both, the old speaker gone and the new one appeared.
+#[derive(Debug, Default)]
+pub struct DeviceChanges {
+    pub appeared: Vec<Speaker>,
+    pub disappeared: Vec<Speaker>,
+}
+
+/// The speakers we know of, by UUID.
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<String, Speaker>,
+    /// Discoveries in a row each known speaker was missing from.
+    missed: HashMap<String, u32>,
+}
+
+impl DeviceRegistry {
+    pub fn devices(&self) -> Vec<Speaker> {
+        let mut devices: Vec<Speaker> = self.devices.values().cloned().collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name).then(a.uuid.cmp(&b.uuid)));
+        devices
+    }
+
+    /// Takes in the speakers one discovery found.
+    pub fn update(&mut self, found: Vec<Speaker>) -> DeviceChanges {
+        let mut changes = DeviceChanges::default();
+        let mut seen: HashMap<String, Speaker> = HashMap::new();
+        for speaker in found {
+            // A static IP may be found through SSDP as well.
+            seen.entry(speaker.uuid.clone()).or_insert(speaker);
+        }
+
+        for (uuid, speaker) in &seen {
+            self.missed.remove(uuid);
+            match self.devices.get(uuid) {
+                Some(known) if known.ip == speaker.ip => {}
+                Some(known) => {
+                    info!("{} moved from {} to {}", speaker.name, known.ip, speaker.ip);
+                    changes.disappeared.push(known.clone());
+                    changes.appeared.push(speaker.clone());
+                }
+                None => {
+                    info!("Found {} at {}", speaker.name, speaker.ip);
+                    changes.appeared.push(speaker.clone());
+                }
+            }
+            self.devices.insert(uuid.clone(), speaker.clone());
+        }
+
+        let missing: Vec<String> = self
+            .devices
+            .keys()
+            .filter(|uuid| !seen.contains_key(*uuid))
+            .cloned()
+            .collect();
+        for uuid in missing {
+            let missed = self.missed.entry(uuid.clone()).or_insert(0);
+            *missed += 1;
+            if *missed >= MISSED_DISCOVERIES {
+                self.missed.remove(&uuid);
+                if let Some(speaker) = self.devices.remove(&uuid) {
+                    info!("Lost {} at {}", speaker.name, speaker.ip);
+                    changes.disappeared.push(speaker);
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// A speaker as discovery would find it, for tests.
+#[cfg(test)]
+pub fn test_speaker(name: &str, uuid: &str, ip: [u8; 4]) -> Speaker {
+    Speaker {
+        ip: ip.into(),
+        model: String::from("Sonos One"),
+        model_number: String::from("S18"),
+        software_version: String::new(),
+        hardware_version: String::new(),
+        serial_number: String::new(),
+        name: name.to_string(),
+        uuid: uuid.to_string(),
+    }
+}
+
+#[test]
+fn test_device_registry() {
+    let names = |speakers: &[Speaker]| -> Vec<String> { speakers.iter().map(|s| s.name.clone()).collect() };
+    let kitchen = test_speaker("Kitchen", "RINCON_KITCHEN", [10, 0, 0, 2]);
+    let office = test_speaker("Office", "RINCON_OFFICE", [10, 0, 1, 3]);
+    let mut registry = DeviceRegistry::default();
+
+    let changes = registry.update(vec![kitchen.clone(), office.clone(), office.clone()]);
+    let mut appeared = names(&changes.appeared);
+    appeared.sort();
+    assert_eq!(appeared, vec!["Kitchen", "Office"]);
+    assert!(changes.disappeared.is_empty());
+    assert_eq!(names(&registry.devices()), vec!["Kitchen", "Office"]);
+
+    // Missing once is not enough to be gone.
+    let changes = registry.update(vec![kitchen.clone()]);
+    assert!(changes.appeared.is_empty() && changes.disappeared.is_empty());
+    let changes = registry.update(vec![kitchen.clone()]);
+    assert_eq!(names(&changes.disappeared), vec!["Office"]);
+    assert_eq!(names(&registry.devices()), vec!["Kitchen"]);
+
+    // A new address is the old speaker gone and a new one found.
+    let moved = test_speaker("Kitchen", "RINCON_KITCHEN", [10, 0, 0, 9]);
+    let changes = registry.update(vec![moved.clone(), office.clone()]);
+    assert_eq!(names(&changes.disappeared), vec!["Kitchen"]);
+    assert_eq!(changes.disappeared[0].ip, kitchen.ip);
+    let mut appeared = names(&changes.appeared);
+    appeared.sort();
+    assert_eq!(appeared, vec!["Kitchen", "Office"]);
+    assert_eq!(registry.devices()[0].ip, moved.ip);
+}
+
+#[test]
+fn test_discovery_schedule() {
+    let interval = Duration::from_secs(300);
+    let mut schedule = DiscoverySchedule::new(interval);
+    assert!(schedule.is_due());
+
+    let delays: Vec<u64> = (0..8).map(|_| schedule.failed().as_secs()).collect();
+    assert_eq!(delays, vec![5, 10, 20, 40, 80, 160, 300, 300]);
+    assert!(!schedule.is_due());
+
+    schedule.succeeded();
+    assert!(!schedule.is_due());
+    assert_eq!(schedule.failed().as_secs(), 5);
+}
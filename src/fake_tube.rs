@@ -0,0 +1,615 @@
+//! A small in-process stand-in for the YouTube Data API v3, used by the tests.
+//!
+//! It serves the subset of `search`, `playlists`, `playlistItems` and `videos`
+//! that `Tube` talks to, keeps everything in memory and binds to a random local
+//! port, so tests can run without network access or Google credentials.
+
+use crate::auth::{OAuthFlow, OAuthSettings};
+use crate::config::Config;
+use actix_web::dev::ServerHandle;
+use actix_web::{delete, get, post, put, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+const DEFAULT_PAGE_SIZE: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct FakeVideo {
+    pub id: String,
+    pub title: String,
+    pub channel_id: String,
+    pub channel_title: String,
+    /// ISO-8601 duration as returned by videos.list, e.g. `PT4M24S`.
+    pub duration: String,
+}
+
+impl FakeVideo {
+    pub fn new(id: &str, title: &str, channel_title: &str, duration: &str) -> Self {
+        FakeVideo {
+            id: id.to_string(),
+            title: title.to_string(),
+            channel_id: format!("UC{}", channel_title.replace(' ', "")),
+            channel_title: channel_title.to_string(),
+            duration: duration.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FakePlaylist {
+    id: String,
+    title: String,
+    description: String,
+    privacy_status: String,
+    /// The bearer token the playlist was created with.
+    owner: String,
+}
+
+#[derive(Debug, Clone)]
+struct FakePlaylistItem {
+    id: String,
+    playlist_id: String,
+    video_id: String,
+}
+
+#[derive(Debug, Default)]
+struct FakeTubeState {
+    videos: Vec<FakeVideo>,
+    playlists: Vec<FakePlaylist>,
+    playlist_items: Vec<FakePlaylistItem>,
+    calls: HashMap<&'static str, usize>,
+    failures: HashMap<String, VecDeque<(u16, String)>>,
+    /// The query parameters of the latest search.
+    last_search: HashMap<String, String>,
+    next_id: u64,
+}
+
+impl FakeTubeState {
+    fn seeded() -> Self {
+        FakeTubeState {
+            videos: vec![
+                FakeVideo::new("kAr40kE0Shp", "Shape of You - Ed Sheeran (Karaoke Version)", "Sing King", "PT4M30S"),
+                FakeVideo::new("JGwWNGJdvx8", "Ed Sheeran - Shape of You (Official Music Video)", "Ed Sheeran", "PT4M24S"),
+                FakeVideo::new("WA4iX5D9Z64", "Taylor Swift - We Are Never Ever Getting Back Together", "TaylorSwiftVEVO", "PT3M52S"),
+                FakeVideo::new("suAR1PYFNYA", "Dua Lipa - Houdini (Official Music Video)", "Dua Lipa", "PT3M5S"),
+                FakeVideo::new("1nt3rXt3nd3", "Deep Forest & Gaudi - Interstellar (Official Extended Mix)", "Deep Forest", "PT9M58S"),
+                FakeVideo::new("1nt3rst3ll4", "Deep Forest & Gaudi - Interstellar (Official Audio)", "Deep Forest", "PT4M50S"),
+                FakeVideo::new("b0hEmCov3r1", "Bohemian Rhapsody - Queen (Acoustic Cover)", "Some Busker", "PT5M40S"),
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn record(&mut self, call: &'static str) {
+        *self.calls.entry(call).or_insert(0) += 1;
+    }
+
+    /// A Google style error response, if one was queued for this call.
+    fn take_failure(&mut self, call: &str) -> Option<HttpResponse> {
+        let (status, reason) = self.failures.get_mut(call)?.pop_front()?;
+        let body = json!({
+            "error": {
+                "code": status,
+                "message": format!("fake {} failure", reason),
+                "errors": [{ "domain": "youtube.fake", "message": format!("fake {} failure", reason), "reason": reason }],
+            }
+        });
+        let status = actix_web::http::StatusCode::from_u16(status).unwrap();
+        Some(HttpResponse::build(status).json(body))
+    }
+
+    /// Puts an item at a zero-based position within its playlist, or at the
+    /// end. Returns the position it ended up at.
+    fn place_item(&mut self, item: FakePlaylistItem, position: Option<usize>) -> usize {
+        let indices: Vec<usize> = self
+            .playlist_items
+            .iter()
+            .enumerate()
+            .filter(|(_, other)| other.playlist_id == item.playlist_id)
+            .map(|(index, _)| index)
+            .collect();
+        match position.and_then(|position| indices.get(position).map(|&index| (position, index))) {
+            Some((position, index)) => {
+                self.playlist_items.insert(index, item);
+                position
+            }
+            None => {
+                self.playlist_items.push(item);
+                indices.len()
+            }
+        }
+    }
+
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{:08}", prefix, self.next_id)
+    }
+}
+
+type SharedState = web::Data<Mutex<FakeTubeState>>;
+
+pub struct FakeTube {
+    pub base_url: String,
+    state: SharedState,
+    handle: ServerHandle,
+}
+
+impl FakeTube {
+    /// Starts the fake API on a random port of the loopback interface.
+    pub async fn start() -> FakeTube {
+        let state: SharedState = web::Data::new(Mutex::new(FakeTubeState::seeded()));
+        let server_state = state.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_state.clone())
+                .service(search)
+                .service(list_playlists)
+                .service(insert_playlist)
+                .service(list_playlist_items)
+                .service(insert_playlist_item)
+                .service(update_playlist_item)
+                .service(delete_playlist_item)
+                .service(list_videos)
+                .service(issue_token)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("Unable to bind fake tube server");
+
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        FakeTube {
+            base_url: format!("http://127.0.0.1:{port}"),
+            state,
+            handle,
+        }
+    }
+
+    /// A config that sends every `Tube` request to this server.
+    pub fn config(&self) -> Config {
+        Config::for_fake_tube(&self.base_url)
+    }
+
+    /// OAuth settings whose token cache holds a token that never expires, so
+    /// only a forced refresh asks this server's `/token` endpoint for another.
+    pub fn oauth_settings(&self, name: &str) -> OAuthSettings {
+        let dir = std::env::temp_dir();
+        let client_secrets_path = dir.join(format!("sonotube_{}_client_secrets.json", name));
+        let secrets = json!({
+            "installed": {
+                "client_id": "fake-client",
+                "client_secret": "fake-secret",
+                "auth_uri": format!("{}/auth", self.base_url),
+                "token_uri": format!("{}/token", self.base_url),
+                "redirect_uris": ["http://localhost"],
+            }
+        });
+        std::fs::write(&client_secrets_path, secrets.to_string()).unwrap();
+
+        let scopes = vec![String::from("fake.scope")];
+        let token_cache_path = dir.join(format!("sonotube_{}_token_cache.json", name));
+        let tokens = json!([{
+            "scopes": scopes,
+            "token": { "access_token": "stale-token", "refresh_token": "fake-refresh", "expires_at": null, "id_token": null },
+        }]);
+        std::fs::write(&token_cache_path, tokens.to_string()).unwrap();
+
+        OAuthSettings {
+            client_secrets_path,
+            token_cache_path,
+            scopes,
+            flow: OAuthFlow::Browser,
+        }
+    }
+
+    /// How many times an endpoint was called, e.g. `"search"` or `"playlistItems.insert"`.
+    pub fn call_count(&self, call: &str) -> usize {
+        self.state.lock().unwrap().calls.get(call).copied().unwrap_or(0)
+    }
+
+    /// Makes the next `times` calls to an endpoint fail with a Google error.
+    pub fn fail_next(&self, call: &str, status: u16, reason: &str, times: usize) {
+        let mut state = self.state.lock().unwrap();
+        let failures = state.failures.entry(call.to_string()).or_default();
+        for _ in 0..times {
+            failures.push_back((status, reason.to_string()));
+        }
+    }
+
+    /// Adds a playlist as if it had been created earlier, returning its id.
+    pub fn add_playlist(&self, title: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        let playlist = FakePlaylist {
+            id: state.next_id("PLfake"),
+            title: title.to_string(),
+            description: String::new(),
+            privacy_status: String::from("private"),
+            owner: String::new(),
+        };
+        state.playlists.push(playlist.clone());
+        playlist.id
+    }
+
+    pub fn playlist_ids(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.playlists.iter().map(|playlist| playlist.id.clone()).collect()
+    }
+
+    pub fn playlist_title(&self, playlist_id: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .playlists
+            .iter()
+            .find(|playlist| playlist.id == playlist_id)
+            .map(|playlist| playlist.title.clone())
+    }
+
+    pub fn playlist_privacy(&self, playlist_id: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .playlists
+            .iter()
+            .find(|playlist| playlist.id == playlist_id)
+            .map(|playlist| playlist.privacy_status.clone())
+    }
+
+    /// A query parameter of the latest search, e.g. `"regionCode"`.
+    pub fn last_search_param(&self, name: &str) -> Option<String> {
+        self.state.lock().unwrap().last_search.get(name).cloned()
+    }
+
+    /// The bearer token a playlist was created with.
+    pub fn playlist_owner(&self, playlist_id: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .playlists
+            .iter()
+            .find(|playlist| playlist.id == playlist_id)
+            .map(|playlist| playlist.owner.clone())
+    }
+
+    pub fn playlist_video_ids(&self, playlist_id: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .playlist_items
+            .iter()
+            .filter(|item| item.playlist_id == playlist_id)
+            .map(|item| item.video_id.clone())
+            .collect()
+    }
+}
+
+impl Drop for FakeTube {
+    fn drop(&mut self) {
+        let handle = self.handle.clone();
+        // Stopping needs a runtime; the test runtime may already be shutting down.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { handle.stop(false).await });
+        }
+    }
+}
+
+fn page<T: Clone>(items: &[T], page_token: &Option<String>, max_results: Option<usize>) -> (Vec<T>, Option<String>) {
+    let start = page_token
+        .as_ref()
+        .and_then(|token| token.parse::<usize>().ok())
+        .unwrap_or(0)
+        .min(items.len());
+    let end = (start + max_results.unwrap_or(DEFAULT_PAGE_SIZE)).min(items.len());
+    let next_page_token = if end < items.len() { Some(end.to_string()) } else { None };
+    (items[start..end].to_vec(), next_page_token)
+}
+
+fn list_response(kind: &str, total: usize, items: Vec<Value>, next_page_token: Option<String>) -> Value {
+    json!({
+        "kind": kind,
+        "etag": "fake-etag",
+        "nextPageToken": next_page_token,
+        "regionCode": "US",
+        "pageInfo": { "totalResults": total, "resultsPerPage": items.len() },
+        "items": items,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchQuery {
+    q: Option<String>,
+    channel_id: Option<String>,
+    max_results: Option<usize>,
+    page_token: Option<String>,
+}
+
+/// Only `channelId` of the search filters narrows the results down.
+#[get("/search")]
+async fn search(
+    state: SharedState,
+    query: web::Query<SearchQuery>,
+    params: web::Query<HashMap<String, String>>,
+) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("search");
+    state.last_search = params.into_inner();
+    if let Some(failure) = state.take_failure("search") {
+        return failure;
+    }
+    let words: Vec<String> = query
+        .q
+        .clone()
+        .unwrap_or_default()
+        .to_lowercase()
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+
+    // Rank by the number of query words found in the title or channel, like a
+    // very naive search engine would.
+    let mut hits: Vec<(usize, &FakeVideo)> = state
+        .videos
+        .iter()
+        .filter(|video| query.channel_id.as_ref().is_none_or(|channel_id| &video.channel_id == channel_id))
+        .map(|video| {
+            let haystack = format!("{} {}", video.title, video.channel_title).to_lowercase();
+            let score = words.iter().filter(|word| haystack.contains(word.as_str())).count();
+            (score, video)
+        })
+        .filter(|(score, _)| *score > 0)
+        .collect();
+    hits.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+
+    let hits: Vec<&FakeVideo> = hits.into_iter().map(|(_, video)| video).collect();
+    let (hits, next_page_token) = page(&hits, &query.page_token, query.max_results);
+    let items = hits
+        .iter()
+        .map(|video| {
+            json!({
+                "kind": "youtube#searchResult",
+                "etag": "fake-etag",
+                "id": { "kind": "youtube#video", "videoId": video.id },
+                "snippet": {
+                    "publishedAt": "2017-01-30T10:57:50Z",
+                    "channelId": video.channel_id,
+                    "title": video.title,
+                    "description": "",
+                    "thumbnails": {},
+                    "channelTitle": video.channel_title,
+                    "liveBroadcastContent": "none",
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(list_response("youtube#searchListResponse", state.videos.len(), items, next_page_token))
+}
+
+fn playlist_json(playlist: &FakePlaylist, item_count: usize) -> Value {
+    json!({
+        "kind": "youtube#playlist",
+        "etag": "fake-etag",
+        "id": playlist.id,
+        "snippet": {
+            "publishedAt": "2024-01-01T00:00:00Z",
+            "channelId": "UCfakechannel",
+            "title": playlist.title,
+            "description": playlist.description,
+            "channelTitle": "fake channel",
+        },
+        "status": { "privacyStatus": playlist.privacy_status },
+        "contentDetails": { "itemCount": item_count },
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListPlaylistsQuery {
+    id: Option<String>,
+    max_results: Option<usize>,
+    page_token: Option<String>,
+}
+
+#[get("/playlists")]
+async fn list_playlists(state: SharedState, query: web::Query<ListPlaylistsQuery>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("playlists.list");
+    if let Some(failure) = state.take_failure("playlists.list") {
+        return failure;
+    }
+    let playlists: Vec<FakePlaylist> = state
+        .playlists
+        .iter()
+        .filter(|playlist| match &query.id {
+            Some(id) => &playlist.id == id,
+            None => true,
+        })
+        .cloned()
+        .collect();
+    let total = playlists.len();
+    let (playlists, next_page_token) = page(&playlists, &query.page_token, query.max_results);
+    let items = playlists
+        .iter()
+        .map(|playlist| {
+            let count = state.playlist_items.iter().filter(|item| item.playlist_id == playlist.id).count();
+            playlist_json(playlist, count)
+        })
+        .collect();
+    HttpResponse::Ok().json(list_response("youtube#playlistListResponse", total, items, next_page_token))
+}
+
+#[post("/playlists")]
+async fn insert_playlist(state: SharedState, request: HttpRequest, body: web::Json<Value>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("playlists.insert");
+    if let Some(failure) = state.take_failure("playlists.insert") {
+        return failure;
+    }
+    let text = |pointer: &str| body.pointer(pointer).and_then(Value::as_str).unwrap_or_default().to_string();
+    let playlist = FakePlaylist {
+        id: state.next_id("PLfake"),
+        title: text("/snippet/title"),
+        description: text("/snippet/description"),
+        privacy_status: text("/status/privacyStatus"),
+        owner: bearer_token(&request),
+    };
+    state.playlists.push(playlist.clone());
+    HttpResponse::Ok().json(playlist_json(&playlist, 0))
+}
+
+fn bearer_token(request: &HttpRequest) -> String {
+    request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn playlist_item_json(item: &FakePlaylistItem, position: usize) -> Value {
+    json!({
+        "kind": "youtube#playlistItem",
+        "etag": "fake-etag",
+        "id": item.id,
+        "snippet": {
+            "playlistId": item.playlist_id,
+            "position": position,
+            "resourceId": { "kind": "youtube#video", "videoId": item.video_id },
+        },
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListPlaylistItemsQuery {
+    playlist_id: String,
+    max_results: Option<usize>,
+    page_token: Option<String>,
+}
+
+#[get("/playlistItems")]
+async fn list_playlist_items(state: SharedState, query: web::Query<ListPlaylistItemsQuery>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("playlistItems.list");
+    if let Some(failure) = state.take_failure("playlistItems.list") {
+        return failure;
+    }
+    let items: Vec<(usize, FakePlaylistItem)> = state
+        .playlist_items
+        .iter()
+        .filter(|item| item.playlist_id == query.playlist_id)
+        .cloned()
+        .enumerate()
+        .collect();
+    let total = items.len();
+    let (items, next_page_token) = page(&items, &query.page_token, query.max_results);
+    let items = items.iter().map(|(position, item)| playlist_item_json(item, *position)).collect();
+    HttpResponse::Ok().json(list_response("youtube#playlistItemListResponse", total, items, next_page_token))
+}
+
+#[post("/playlistItems")]
+async fn insert_playlist_item(state: SharedState, body: web::Json<Value>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("playlistItems.insert");
+    if let Some(failure) = state.take_failure("playlistItems.insert") {
+        return failure;
+    }
+    let text = |pointer: &str| body.pointer(pointer).and_then(Value::as_str).unwrap_or_default().to_string();
+    let item = FakePlaylistItem {
+        id: state.next_id("PLIfake"),
+        playlist_id: text("/snippet/playlistId"),
+        video_id: text("/snippet/resourceId/videoId"),
+    };
+    let position = body.pointer("/snippet/position").and_then(Value::as_u64).map(|p| p as usize);
+    let position = state.place_item(item.clone(), position);
+    HttpResponse::Ok().json(playlist_item_json(&item, position))
+}
+
+/// Only moving an item is supported.
+#[put("/playlistItems")]
+async fn update_playlist_item(state: SharedState, body: web::Json<Value>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("playlistItems.update");
+    if let Some(failure) = state.take_failure("playlistItems.update") {
+        return failure;
+    }
+    let id = body.pointer("/id").and_then(Value::as_str).unwrap_or_default();
+    let index = match state.playlist_items.iter().position(|item| item.id == id) {
+        Some(index) => index,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let item = state.playlist_items.remove(index);
+    let position = body.pointer("/snippet/position").and_then(Value::as_u64).map(|p| p as usize);
+    let position = state.place_item(item.clone(), position);
+    HttpResponse::Ok().json(playlist_item_json(&item, position))
+}
+
+#[derive(Deserialize)]
+struct DeletePlaylistItemQuery {
+    id: String,
+}
+
+#[delete("/playlistItems")]
+async fn delete_playlist_item(state: SharedState, query: web::Query<DeletePlaylistItemQuery>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("playlistItems.delete");
+    if let Some(failure) = state.take_failure("playlistItems.delete") {
+        return failure;
+    }
+    match state.playlist_items.iter().position(|item| item.id == query.id) {
+        Some(index) => {
+            state.playlist_items.remove(index);
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListVideosQuery {
+    id: String,
+}
+
+#[get("/videos")]
+async fn list_videos(state: SharedState, query: web::Query<ListVideosQuery>) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("videos.list");
+    if let Some(failure) = state.take_failure("videos.list") {
+        return failure;
+    }
+    let ids: HashMap<&str, usize> = query.id.split(',').enumerate().map(|(i, id)| (id, i)).collect();
+    let mut videos: Vec<&FakeVideo> = state.videos.iter().filter(|video| ids.contains_key(video.id.as_str())).collect();
+    videos.sort_by_key(|video| ids[video.id.as_str()]);
+    let items = videos
+        .iter()
+        .map(|video| {
+            json!({
+                "kind": "youtube#video",
+                "etag": "fake-etag",
+                "id": video.id,
+                "snippet": {
+                    "publishedAt": "2017-01-30T10:57:50Z",
+                    "channelId": video.channel_id,
+                    "title": video.title,
+                    "description": "",
+                    "thumbnails": {},
+                    "channelTitle": video.channel_title,
+                },
+                "contentDetails": { "duration": video.duration },
+            })
+        })
+        .collect::<Vec<_>>();
+    let total = items.len();
+    HttpResponse::Ok().json(list_response("youtube#videoListResponse", total, items, None))
+}
+
+#[post("/token")]
+async fn issue_token(state: SharedState) -> impl Responder {
+    let mut state = state.lock().unwrap();
+    state.record("token");
+    let access_token = state.next_id("fresh-token");
+    HttpResponse::Ok().json(json!({ "access_token": access_token, "expires_in": 3600, "token_type": "Bearer" }))
+}
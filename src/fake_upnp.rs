@@ -0,0 +1,350 @@
+//! A stand-in for a Sonos speaker's AVTransport and ZoneGroupTopology
+//! services, used by the tests.
+//!
+//! It accepts GENA SUBSCRIBE, renewal and UNSUBSCRIBE requests and sends its
+//! subscribers NOTIFY requests with `LastChange` and `ZoneGroupState`
+//! documents shaped like the ones real speakers send, and answers
+//! `GetZoneGroupState`, so event handling can be tested offline.
+
+use crate::upnp::{AVTRANSPORT_EVENT_PATH, TOPOLOGY_CONTROL_PATH, TOPOLOGY_EVENT_PATH};
+use actix_web::dev::ServerHandle;
+use actix_web::http::Method;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const TIMEOUT_SECS: u64 = 1800;
+
+struct FakeSubscription {
+    /// The event path subscribed to.
+    service: String,
+    sid: String,
+    callback_url: String,
+    seq: u64,
+}
+
+struct FakeDeviceState {
+    transport_state: String,
+    uri: String,
+    artist: String,
+    title: String,
+    duration: String,
+    /// Coordinator UUIDs with their members' UUIDs and rooms.
+    groups: Vec<(String, Vec<(String, String)>)>,
+    subscriptions: Vec<FakeSubscription>,
+    calls: HashMap<&'static str, usize>,
+    next_sid: u64,
+}
+
+type SharedState = Arc<Mutex<FakeDeviceState>>;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl FakeDeviceState {
+    /// A NOTIFY body. Track details are left out unless `with_track`, as
+    /// speakers do for events that only change the transport state.
+    fn notify_body(&self, with_track: bool) -> String {
+        let mut variables = format!(r#"<TransportState val="{}"/>"#, self.transport_state);
+        if with_track {
+            let metadata = format!(
+                r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/"><item id="-1" parentID="-1"><res duration="{}">{}</res><dc:title>{}</dc:title><dc:creator>{}</dc:creator></item></DIDL-Lite>"#,
+                self.duration,
+                escape(&self.uri),
+                escape(&self.title),
+                escape(&self.artist)
+            );
+            variables.push_str(&format!(
+                r#"<CurrentTrack val="1"/><CurrentTrackURI val="{}"/><CurrentTrackDuration val="{}"/><CurrentTrackMetaData val="{}"/>"#,
+                escape(&self.uri),
+                self.duration,
+                escape(&metadata)
+            ));
+        }
+        let last_change = format!(
+            r#"<Event xmlns="urn:schemas-upnp-org:metadata-1-0/AVT/"><InstanceID val="0">{}</InstanceID></Event>"#,
+            variables
+        );
+        format!(
+            r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><LastChange>{}</LastChange></e:property></e:propertyset>"#,
+            escape(&last_change)
+        )
+    }
+
+    fn zone_group_state(&self) -> String {
+        let groups: String = self
+            .groups
+            .iter()
+            .map(|(coordinator, members)| {
+                let members: String = members
+                    .iter()
+                    .map(|(uuid, room)| {
+                        format!(
+                            r#"<ZoneGroupMember UUID="{}" Location="http://127.0.0.1:1400/xml/device_description.xml" ZoneName="{}"/>"#,
+                            uuid,
+                            escape(room)
+                        )
+                    })
+                    .collect();
+                format!(r#"<ZoneGroup Coordinator="{0}" ID="{0}:1">{1}</ZoneGroup>"#, coordinator, members)
+            })
+            .collect();
+        format!(
+            "<ZoneGroupState><ZoneGroups>{}</ZoneGroups><VanishedDevices/></ZoneGroupState>",
+            groups
+        )
+    }
+
+    fn topology_body(&self) -> String {
+        format!(
+            r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><ZoneGroupState>{}</ZoneGroupState></e:property><e:property><AvailableSoftwareUpdate>&lt;UpdateItem/&gt;</AvailableSoftwareUpdate></e:property></e:propertyset>"#,
+            escape(&self.zone_group_state())
+        )
+    }
+
+    /// What a new subscriber to a service is sent first.
+    fn initial_body(&self, service: &str) -> String {
+        match service {
+            TOPOLOGY_EVENT_PATH => self.topology_body(),
+            _ => self.notify_body(true),
+        }
+    }
+
+    fn record(&mut self, call: &'static str) {
+        *self.calls.entry(call).or_insert(0) += 1;
+    }
+}
+
+async fn send_notify(callback_url: String, sid: String, seq: u64, body: String) {
+    let _ = reqwest::Client::new()
+        .request(reqwest::Method::from_bytes(b"NOTIFY").unwrap(), callback_url)
+        .header("CONTENT-TYPE", "text/xml; charset=\"utf-8\"")
+        .header("NT", "upnp:event")
+        .header("NTS", "upnp:propchange")
+        .header("SID", sid)
+        .header("SEQ", seq.to_string())
+        .body(body)
+        .send()
+        .await;
+}
+
+fn header(request: &HttpRequest, name: &str) -> Option<String> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+async fn subscribe(request: HttpRequest, state: web::Data<SharedState>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    if let Some(sid) = header(&request, "SID") {
+        state.record("renew");
+        if !state.subscriptions.iter().any(|subscription| subscription.sid == sid) {
+            return HttpResponse::PreconditionFailed().finish();
+        }
+        return HttpResponse::Ok()
+            .insert_header(("SID", sid))
+            .insert_header(("TIMEOUT", format!("Second-{}", TIMEOUT_SECS)))
+            .finish();
+    }
+
+    state.record("subscribe");
+    let callback_url = match header(&request, "CALLBACK") {
+        Some(callback) if header(&request, "NT").as_deref() == Some("upnp:event") => {
+            callback.trim_start_matches('<').trim_end_matches('>').to_string()
+        }
+        _ => return HttpResponse::PreconditionFailed().finish(),
+    };
+    state.next_sid += 1;
+    let sid = format!("uuid:RINCON_FAKE0001400_sub{:010}", state.next_sid);
+    let service = request.path().to_string();
+    let body = state.initial_body(&service);
+    state.subscriptions.push(FakeSubscription {
+        service,
+        sid: sid.clone(),
+        callback_url: callback_url.clone(),
+        seq: 1,
+    });
+    // Speakers follow up a new subscription with their whole state.
+    tokio::spawn(send_notify(callback_url, sid.clone(), 0, body));
+    HttpResponse::Ok()
+        .insert_header(("SID", sid))
+        .insert_header(("TIMEOUT", format!("Second-{}", TIMEOUT_SECS)))
+        .finish()
+}
+
+async fn unsubscribe(request: HttpRequest, state: web::Data<SharedState>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.record("unsubscribe");
+    let sid = header(&request, "SID").unwrap_or_default();
+    let before = state.subscriptions.len();
+    state.subscriptions.retain(|subscription| subscription.sid != sid);
+    if state.subscriptions.len() < before {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::PreconditionFailed().finish()
+    }
+}
+
+async fn get_zone_group_state(request: HttpRequest, state: web::Data<SharedState>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.record("GetZoneGroupState");
+    if !header(&request, "SOAPACTION").is_some_and(|action| action.contains("#GetZoneGroupState")) {
+        return HttpResponse::InternalServerError().finish();
+    }
+    let body = format!(
+        r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:GetZoneGroupStateResponse xmlns:u="urn:schemas-upnp-org:service:ZoneGroupTopology:1"><ZoneGroupState>{}</ZoneGroupState></u:GetZoneGroupStateResponse></s:Body></s:Envelope>"#,
+        escape(&state.zone_group_state())
+    );
+    HttpResponse::Ok()
+        .content_type("text/xml; charset=\"utf-8\"")
+        .body(body)
+}
+
+pub struct FakeUpnpDevice {
+    pub base_url: String,
+    state: SharedState,
+    handle: ServerHandle,
+}
+
+impl FakeUpnpDevice {
+    /// Starts a device playing "Houdini" by Dua Lipa on a random loopback port.
+    pub async fn start() -> FakeUpnpDevice {
+        let state: SharedState = Arc::new(Mutex::new(FakeDeviceState {
+            transport_state: String::from("PLAYING"),
+            uri: String::from("x-sonos-spotify:houdini"),
+            artist: String::from("Dua Lipa"),
+            title: String::from("Houdini"),
+            duration: String::from("0:03:05"),
+            groups: vec![(
+                String::from("RINCON_FAKE0001400"),
+                vec![(String::from("RINCON_FAKE0001400"), String::from("Living Room"))],
+            )],
+            subscriptions: Vec::new(),
+            calls: HashMap::new(),
+            next_sid: 0,
+        }));
+        let server_state = web::Data::new(state.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(server_state.clone())
+                .service(
+                    web::resource([AVTRANSPORT_EVENT_PATH, TOPOLOGY_EVENT_PATH])
+                        .route(web::method(Method::from_bytes(b"SUBSCRIBE").unwrap()).to(subscribe))
+                        .route(web::method(Method::from_bytes(b"UNSUBSCRIBE").unwrap()).to(unsubscribe)),
+                )
+                .route(TOPOLOGY_CONTROL_PATH, web::post().to(get_zone_group_state))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("Unable to bind fake UPnP device");
+
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        FakeUpnpDevice {
+            base_url: format!("http://127.0.0.1:{port}"),
+            state,
+            handle,
+        }
+    }
+
+    /// The URL of one of the services, e.g. `AVTRANSPORT_EVENT_PATH`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Callback URLs of the current subscriptions.
+    pub fn subscribers(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .subscriptions
+            .iter()
+            .map(|subscription| subscription.callback_url.clone())
+            .collect()
+    }
+
+    /// How many times a request was made: `"subscribe"`, `"renew"`,
+    /// `"unsubscribe"` or `"GetZoneGroupState"`.
+    pub fn call_count(&self, call: &str) -> usize {
+        self.state.lock().unwrap().calls.get(call).copied().unwrap_or(0)
+    }
+
+    /// Starts playing a track and tells the subscribers.
+    pub async fn play(&self, uri: &str, artist: &str, title: &str, duration: &str) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.transport_state = String::from("PLAYING");
+            state.uri = uri.to_string();
+            state.artist = artist.to_string();
+            state.title = title.to_string();
+            state.duration = duration.to_string();
+        }
+        self.notify(AVTRANSPORT_EVENT_PATH, |state| state.notify_body(true))
+            .await;
+    }
+
+    pub async fn pause(&self) {
+        self.state.lock().unwrap().transport_state = String::from("PAUSED_PLAYBACK");
+        self.notify(AVTRANSPORT_EVENT_PATH, |state| state.notify_body(false))
+            .await;
+    }
+
+    /// Replaces the zone groups, given as coordinators with their members'
+    /// UUIDs and rooms, and tells the topology subscribers.
+    pub async fn regroup(&self, groups: &[(&str, &[(&str, &str)])]) {
+        self.state.lock().unwrap().groups = groups
+            .iter()
+            .map(|(coordinator, members)| {
+                let members = members
+                    .iter()
+                    .map(|(uuid, room)| (uuid.to_string(), room.to_string()))
+                    .collect();
+                (coordinator.to_string(), members)
+            })
+            .collect();
+        self.notify(TOPOLOGY_EVENT_PATH, FakeDeviceState::topology_body).await;
+    }
+
+    async fn notify(&self, service: &str, body: impl Fn(&FakeDeviceState) -> String) {
+        let notifications: Vec<(String, String, u64, String)> = {
+            let mut state = self.state.lock().unwrap();
+            let body = body(&state);
+            state
+                .subscriptions
+                .iter_mut()
+                .filter(|subscription| subscription.service == service)
+                .map(|subscription| {
+                    subscription.seq += 1;
+                    (
+                        subscription.callback_url.clone(),
+                        subscription.sid.clone(),
+                        subscription.seq - 1,
+                        body.clone(),
+                    )
+                })
+                .collect()
+        };
+        for (callback_url, sid, seq, body) in notifications {
+            send_notify(callback_url, sid, seq, body).await;
+        }
+    }
+}
+
+impl Drop for FakeUpnpDevice {
+    fn drop(&mut self) {
+        let handle = self.handle.clone();
+        // Stopping needs a runtime; the test runtime may already be shutting down.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { handle.stop(false).await });
+        }
+    }
+}
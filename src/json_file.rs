@@ -0,0 +1,76 @@
+//! Reads and writes the JSON files sonotube keeps its state in between runs.
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where a file lives in the cache directory, for state sonotube can rebuild.
+pub fn cache_path(file_name: &str) -> PathBuf {
+    let mut path = dirs::cache_dir().expect("The cache directory was not found.");
+    path.push(file_name);
+    path
+}
+
+/// Where a file lives in the home directory, for files people edit.
+pub fn home_path(file_name: &str) -> PathBuf {
+    let mut path = dirs::home_dir().expect("The home directory was not found.");
+    path.push(file_name);
+    path
+}
+
+/// The contents of a file, or `None` if there is none yet. A file that
+/// cannot be read or parsed is logged as the `what` it should have held
+/// and treated as missing.
+pub fn load<T: DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+    let serialized = match std::fs::read_to_string(path) {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            warn!("Unable to read {} {:?}: {}", what, path, e);
+            return None;
+        }
+    };
+    match serde_json::from_str(&serialized) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Ignoring unreadable {} {:?}: {}", what, path, e);
+            None
+        }
+    }
+}
+
+pub fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let file = create(path)?;
+    serde_json::to_writer(file, value)?;
+    Ok(())
+}
+
+/// Saves a file indented, for files people edit.
+pub fn save_pretty<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let file = create(path)?;
+    serde_json::to_writer_pretty(file, value)?;
+    Ok(())
+}
+
+fn create(path: &Path) -> io::Result<std::fs::File> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)
+}
+
+#[test]
+fn test_load_save_json_file() {
+    let path = cache_path(".test_json_file.json");
+    save(&path, &vec![1, 2, 3]).unwrap();
+    assert_eq!(load::<Vec<u32>>(&path, "numbers"), Some(vec![1, 2, 3]));
+    assert_eq!(load::<String>(&path, "text"), None);
+    assert_eq!(
+        load::<Vec<u32>>(&cache_path(".test_json_file_missing.json"), "numbers"),
+        None
+    );
+    // A directory cannot be written.
+    assert!(save(&std::env::temp_dir(), &1).is_err());
+}
@@ -1,21 +1,54 @@
 
 use std::io;
 use std::sync::atomic::AtomicBool;
-use std::sync::mpsc;
+use tokio::sync::mpsc;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use env_logger::Env;
+use log::error;
 use sonos::{self, Track};
-use config::Config;
+use std::collections::HashMap;
+use config::{Config, DEFAULT_ACCOUNT};
+use match_cache::{MatchCache, SharedMatchCache, MATCH_CACHE};
+use overrides::{Overrides, SharedOverrides, OVERRIDES};
+use playlist_parts::{PlaylistParts, PLAYLIST_PARTS};
+use deferred::{DeferredTracks, DEFERRED};
+use quota::{QuotaLedger, SharedQuotaLedger, QUOTA_LEDGER};
 use tube::Tube;
-use sonotube::SonoTube;
+use sonotube::{Play, SonoTube};
 use models::TubeTrack;
+#[cfg(test)]
+use models::test_track;
+use template::PlaylistVars;
+use std::time::Duration;
+
+/// How often the tube monitor checks for a quota reset while no tracks play.
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 mod models;
+mod auth;
 mod tube;
 mod toptastic;
 mod config;
 mod sonotube;
+mod play_rules;
+mod matcher;
+mod normalize;
+mod video_filter;
+mod match_cache;
+mod overrides;
+mod quota;
+mod api;
+mod playlist_parts;
+mod deferred;
+mod json_file;
+mod template;
+mod upnp;
+mod discovery;
+#[cfg(test)]
+mod fake_tube;
+#[cfg(test)]
+mod fake_upnp;
 
 impl From<Track> for TubeTrack {
     fn from(track: Track) -> Self {
@@ -24,6 +57,11 @@ impl From<Track> for TubeTrack {
             title: track.title,
             artist: track.artist,
             video_id: None,
+            duration_secs: if track.duration.is_zero() {
+                None
+            } else {
+                Some(track.duration.as_secs())
+            },
         }
     }
 }
@@ -33,8 +71,43 @@ async fn main() {
 
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let config = Config::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => {}
+        ["auth", "login"] => {
+            auth_login(&config, DEFAULT_ACCOUNT).await;
+            return;
+        }
+        ["auth", "login", account] => {
+            auth_login(&config, account).await;
+            return;
+        }
+        _ => {
+            eprintln!("Usage: sonotube [auth login [account]]");
+            std::process::exit(2);
+        }
+    }
+    if config.create_sonotube_play_list() || config.create_toptastic_play_list() {
+        for account in config.account_names() {
+            if let Err(problems) = config.for_account(&account).unwrap().validate_oauth() {
+                eprintln!("OAuth is not set up for the {} account:\n{}", account, problems);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Err(problem) = config.video_filter() {
+        eprintln!("The video filter is not usable: {}", problem);
+        std::process::exit(1);
+    }
+    if let Err(problem) = config.sonos_speaker_ips() {
+        eprintln!("The Sonos speaker IPs are not usable: {}", problem);
+        std::process::exit(1);
+    }
+    let match_cache = MatchCache::load(MATCH_CACHE, config.match_cache_ttl()).shared();
+    let quota = QuotaLedger::load(QUOTA_LEDGER, config.quota_budget(), config.quota_reserve()).shared();
+    let overrides = Overrides::load(OVERRIDES).shared();
    
-    let (sender, receiver) = mpsc::channel::<Track>();
+    let (sender, receiver) = mpsc::unbounded_channel::<Play>();
     let track_monitor_flag = Arc::new(AtomicBool::new(true));
 
     println!("Hit enter to quit");
@@ -42,9 +115,10 @@ async fn main() {
 
     let track_monitor_handle =
         SonoTube::start_sonos_track_monitor(sender, track_monitor_flag.clone(), config.clone()).await;
-    let tube_monitor_handle = start_tube_monitor(receiver).await;
+    let tubes = account_tubes(&config, match_cache.clone(), quota.clone(), overrides.clone());
+    let tube_monitor_handle = start_tube_monitor(receiver, config.clone(), tubes, quota.clone()).await;
     
-    start_toptastic_server(&config).await.expect("toptastic server failed");
+    start_toptastic_server(&config, match_cache, quota, overrides).await.expect("toptastic server failed");
 
     track_monitor_handle.await.expect("track_monitor panicked");
     tube_monitor_handle.await.expect("tube_monitor panicked");
@@ -52,6 +126,24 @@ async fn main() {
     println!("Done.");
 }
 
+/// Authorizes sonotube once for an account and writes the token cache the monitor reads.
+async fn auth_login(config: &Config, account: &str) {
+    let settings = match config.for_account(account) {
+        Some(config) => config.oauth_settings(),
+        None => {
+            eprintln!("There is no {} account. Accounts: {}", account, config.account_names().join(", "));
+            std::process::exit(1);
+        }
+    };
+    match auth::login(&settings).await {
+        Ok(_) => println!("Authorized. Token cached at {:?}", settings.token_cache_path),
+        Err(e) => {
+            eprintln!("Authorization failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn wait_for_enter_key(flag: Arc<AtomicBool>) {
     tokio::spawn(async move {
         match io::stdin().read_line(&mut String::new()) {
@@ -64,27 +156,186 @@ fn wait_for_enter_key(flag: Arc<AtomicBool>) {
     });
 }
 
-async fn start_toptastic_server(config: &Config) -> std::io::Result<()> {
+async fn start_toptastic_server(
+    config: &Config,
+    match_cache: SharedMatchCache,
+    quota: SharedQuotaLedger,
+    overrides: SharedOverrides,
+) -> std::io::Result<()> {
     println!("Starting toptastic server...");
 
-    let toptastic = toptastic::TopTastic::new(config).await.unwrap();
+    let toptastic = toptastic::TopTastic::new(config, match_cache, quota)
+        .await
+        .unwrap()
+        .with_overrides(overrides);
     toptastic.start_server().await
 }
 
-async fn start_tube_monitor(receiver: mpsc::Receiver<Track>) -> JoinHandle<()> {
+/// One tube per account, each with the playlist parts and deferred tracks
+/// it kept from earlier runs.
+fn account_tubes(
+    config: &Config,
+    match_cache: SharedMatchCache,
+    quota: SharedQuotaLedger,
+    overrides: SharedOverrides,
+) -> HashMap<String, Tube> {
+    config
+        .account_names()
+        .into_iter()
+        .map(|account| {
+            let account_config = config.for_account(&account).unwrap();
+            let tube = Tube::new(&account_config, match_cache.clone(), quota.clone())
+                .with_playlist(account_config.playlist_target())
+                .with_playlist_parts(PlaylistParts::load(&account_file(PLAYLIST_PARTS, &account)))
+                .with_deferred(DeferredTracks::load(&account_file(DEFERRED, &account)))
+                .with_overrides(overrides.clone());
+            (account, tube)
+        })
+        .collect()
+}
+
+async fn start_tube_monitor(
+    mut receiver: mpsc::UnboundedReceiver<Play>,
+    config: Config,
+    mut tubes: HashMap<String, Tube>,
+    quota: SharedQuotaLedger,
+) -> JoinHandle<()> {
     println!("Starting tube monitor...");
     tokio::spawn(async move {
-        let mut tube = tube::Tube::new();
-        for track in receiver {
-            let tube_track = TubeTrack::from(track);
-            let (title, description) = Tube::generate_sonotube_title_and_description("sonotube");
-            tube.process_track(&tube_track, &title, &description).await;
+        let mut quota_day = quota.lock().unwrap().usage().day;
+        loop {
+            // Wake up now and then to notice the quota reset, even without plays.
+            let play = match tokio::time::timeout(QUOTA_CHECK_INTERVAL, receiver.recv()).await {
+                Ok(Some(play)) => Some(play),
+                Ok(None) => break,
+                Err(_) => None,
+            };
+            retry_deferred_on_new_day(&mut tubes, &quota, &mut quota_day, &config).await;
+            let play = match play {
+                Some(play) => play,
+                None => continue,
+            };
+
+            let account = match &play.room {
+                Some(room) => config.account_for_room(room),
+                None => String::from(DEFAULT_ACCOUNT),
+            };
+            let tube = match tubes.get_mut(&account) {
+                Some(tube) => tube,
+                None => continue,
+            };
+            let tube_track = TubeTrack::from(play.track);
+            // Only used if this track is the one that creates the playlist.
+            let vars = PlaylistVars::new("sonotube")
+                .with_room(play.room.clone())
+                .with_tracks(std::slice::from_ref(&tube_track));
+            let (title, description) = Tube::generate_title_and_description(&config, &vars);
+            tube.process_deferred(&title, &description).await;
+            // One bad track should not stop the monitor.
+            if let Err(e) = tube.process_track(&tube_track, &title, &description).await {
+                error!("Unable to add {} by {} for {}: {}", tube_track.title, tube_track.artist, account, e);
+            }
         }
     })
 }
 
+/// Retries every account's deferred tracks once the quota has reset for a new Pacific day.
+async fn retry_deferred_on_new_day(
+    tubes: &mut HashMap<String, Tube>,
+    quota: &SharedQuotaLedger,
+    quota_day: &mut String,
+    config: &Config,
+) {
+    let today = quota.lock().unwrap().usage().day;
+    if today == *quota_day {
+        return;
+    }
+    *quota_day = today;
+    let vars = PlaylistVars::new("sonotube");
+    let (title, description) = Tube::generate_title_and_description(config, &vars);
+    for tube in tubes.values_mut() {
+        tube.process_deferred(&title, &description).await;
+    }
+}
+
+/// Each account keeps state files of its own.
+fn account_file(file_name: &str, account: &str) -> String {
+    if account == DEFAULT_ACCOUNT {
+        String::from(file_name)
+    } else {
+        format!("{}.{}", file_name, account)
+    }
+}
+
 #[tokio::test]
 async fn test_config() {
     let config = Config::new();
     config._save(".test_sonotube_config.json");
 }
+
+#[tokio::test]
+async fn test_tube_monitor_names_playlist_after_room() {
+    let fake = fake_tube::FakeTube::start().await;
+    let mut config = serde_json::to_value(fake.config()).unwrap();
+    config["playlistTitleTemplate"] = serde_json::json!("{room} plays");
+    let config: Config = serde_json::from_value(config).unwrap();
+
+    let (sender, receiver) = mpsc::unbounded_channel::<Play>();
+    let track = Track {
+        title: String::from("shape of you"),
+        artist: String::from("ed sheeran"),
+        album: None,
+        queue_position: 1,
+        uri: String::from("uri:shape"),
+        duration: std::time::Duration::ZERO,
+        running_time: std::time::Duration::ZERO,
+    };
+    sender
+        .send(Play {
+            track,
+            room: Some(String::from("Kitchen")),
+        })
+        .unwrap();
+    drop(sender);
+
+    let match_cache = MatchCache::in_memory(config.match_cache_ttl()).shared();
+    let quota = QuotaLedger::in_memory(config.quota_budget(), config.quota_reserve()).shared();
+    let tubes = HashMap::from([(
+        String::from(DEFAULT_ACCOUNT),
+        Tube::new(&config, match_cache, quota.clone()),
+    )]);
+    start_tube_monitor(receiver, config, tubes, quota).await.await.unwrap();
+
+    let titles: Vec<Option<String>> = fake.playlist_ids().iter().map(|id| fake.playlist_title(id)).collect();
+    assert!(titles.contains(&Some(String::from("Kitchen plays"))));
+}
+
+#[tokio::test]
+async fn test_deferred_tracks_retried_on_new_day() {
+    let fake = fake_tube::FakeTube::start().await;
+    let config = fake.config();
+    let match_cache = MatchCache::in_memory(config.match_cache_ttl()).shared();
+    let quota = QuotaLedger::in_memory(config.quota_budget(), config.quota_reserve()).shared();
+    let mut tubes = HashMap::from([(
+        String::from(DEFAULT_ACCOUNT),
+        Tube::new(&config, match_cache, quota.clone()),
+    )]);
+    let track = test_track("uri:shape", "shape of you", "ed sheeran");
+
+    quota.lock().unwrap().exhaust();
+    let tube = tubes.get_mut(DEFAULT_ACCOUNT).unwrap();
+    assert!(tube.process_track(&track, "test", "test").await.unwrap().is_none());
+
+    // Nothing is retried while the day lasts.
+    let mut quota_day = quota.lock().unwrap().usage().day;
+    retry_deferred_on_new_day(&mut tubes, &quota, &mut quota_day, &config).await;
+    assert_eq!(fake.call_count("search"), 0);
+
+    // A fresh ledger on a new day has room for them again.
+    *quota.lock().unwrap() = QuotaLedger::in_memory(config.quota_budget(), config.quota_reserve());
+    let mut quota_day = String::from("1999-12-31");
+    retry_deferred_on_new_day(&mut tubes, &quota, &mut quota_day, &config).await;
+    assert_eq!(fake.call_count("search"), 1);
+    assert_eq!(quota_day, quota.lock().unwrap().usage().day);
+    assert!(tubes[DEFAULT_ACCOUNT].clone().take_deferred().is_empty());
+}
@@ -0,0 +1,172 @@
+//! Remembers which video a track was matched to, so a song we have matched
+//! before does not cost another search.
+
+use crate::json_file;
+use crate::matcher::VideoMatch;
+use crate::models::TubeTrack;
+#[cfg(test)]
+use crate::models::test_track;
+use crate::normalize;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const MATCH_CACHE: &str = ".sonotube_matches.json";
+
+pub type SharedMatchCache = Arc<Mutex<MatchCache>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedMatch {
+    pub video_id: String,
+    /// Unix timestamp of when the match was made.
+    pub matched_at: i64,
+    pub confidence: f64,
+}
+
+#[derive(Debug)]
+pub struct MatchCache {
+    file_name: Option<String>,
+    ttl: Duration,
+    entries: HashMap<String, CachedMatch>,
+}
+
+impl MatchCache {
+    /// Loads the cache from the cache directory, dropping expired entries.
+    pub fn load(file_name: &str, ttl: Duration) -> Self {
+        let entries = json_file::load(&json_file::cache_path(file_name), "match cache").unwrap_or_default();
+        let mut cache = MatchCache {
+            file_name: Some(file_name.to_string()),
+            ttl,
+            entries,
+        };
+        cache.prune();
+        info!("Loaded {} cached matches", cache.entries.len());
+        cache
+    }
+
+    /// A cache that is never written to disk.
+    #[cfg(test)]
+    pub fn in_memory(ttl: Duration) -> Self {
+        MatchCache {
+            file_name: None,
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn shared(self) -> SharedMatchCache {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Normalized artist+title key, so case, punctuation, diacritics and
+    /// spacing differences between sources still hit the same entry.
+    pub fn key(artist: &str, title: &str) -> String {
+        let normalize = |text: &str| normalize::words(text).join(" ");
+        format!("{}|{}", normalize(artist), normalize(title))
+    }
+
+    fn is_expired(&self, entry: &CachedMatch) -> bool {
+        let age = chrono::Utc::now().timestamp() - entry.matched_at;
+        age < 0 || age as u64 >= self.ttl.as_secs()
+    }
+
+    pub fn get(&self, track: &TubeTrack) -> Option<&CachedMatch> {
+        self.entries
+            .get(&MatchCache::key(&track.artist, &track.title))
+            .filter(|entry| !self.is_expired(entry))
+    }
+
+    pub fn insert(&mut self, track: &TubeTrack, video_match: &VideoMatch) {
+        let entry = CachedMatch {
+            video_id: video_match.video_id.clone(),
+            matched_at: chrono::Utc::now().timestamp(),
+            confidence: video_match.confidence,
+        };
+        self.entries.insert(MatchCache::key(&track.artist, &track.title), entry);
+        self.save();
+    }
+
+    /// Keys of the entries matched to one of these videos, mapped to the video.
+    /// Expired entries count too; they still say which track a video was for.
+    pub fn keys_for_videos(&self, video_ids: &HashSet<String>) -> HashMap<String, String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| video_ids.contains(&entry.video_id))
+            .map(|(key, entry)| (key.clone(), entry.video_id.clone()))
+            .collect()
+    }
+
+    /// Forgets the match for a track. Returns whether there was one.
+    pub fn invalidate(&mut self, artist: &str, title: &str) -> bool {
+        let removed = self.entries.remove(&MatchCache::key(artist, title)).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    fn prune(&mut self) {
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| self.is_expired(entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.entries.remove(&key);
+        }
+    }
+
+    fn save(&self) {
+        let file_name = match &self.file_name {
+            Some(file_name) => file_name,
+            None => return,
+        };
+        if let Err(e) = json_file::save(&json_file::cache_path(file_name), &self.entries) {
+            warn!("Unable to save the match cache: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+fn video_match(video_id: &str) -> VideoMatch {
+    VideoMatch {
+        video_id: video_id.to_string(),
+        title: String::new(),
+        channel_title: String::new(),
+        confidence: 0.9,
+    }
+}
+
+#[test]
+fn test_match_cache_ttl_and_invalidation() {
+    let mut cache = MatchCache::in_memory(Duration::from_secs(3600));
+    cache.insert(&test_track("id", "Shape of You", "Ed Sheeran"), &video_match("JGwWNGJdvx8"));
+
+    let hit = cache.get(&test_track("id", "shape of you!", "ED  SHEERAN")).unwrap();
+    assert_eq!(hit.video_id, "JGwWNGJdvx8");
+
+    cache.entries.values_mut().for_each(|entry| entry.matched_at -= 7200);
+    assert!(cache.get(&test_track("id", "Shape of You", "Ed Sheeran")).is_none());
+
+    cache.insert(&test_track("id", "Houdini", "Dua Lipa"), &video_match("suAR1PYFNYA"));
+    assert!(cache.invalidate("Dua Lipa", "Houdini"));
+    assert!(!cache.invalidate("Dua Lipa", "Houdini"));
+}
+
+#[test]
+fn test_load_save_match_cache() {
+    let test_file_name = ".test_match_cache.json";
+    let ttl = Duration::from_secs(3600);
+    let mut cache = MatchCache::load(test_file_name, ttl);
+    cache.insert(&test_track("id", "Shape of You", "Ed Sheeran"), &video_match("JGwWNGJdvx8"));
+    assert!(json_file::cache_path(test_file_name).exists());
+
+    let loaded = MatchCache::load(test_file_name, ttl);
+    let hit = loaded.get(&test_track("id", "Shape of You", "Ed Sheeran")).unwrap();
+    assert_eq!(hit.video_id, "JGwWNGJdvx8");
+    assert_eq!(hit.confidence, 0.9);
+}
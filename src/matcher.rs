@@ -0,0 +1,194 @@
+//! Ranks YouTube search results against a track, so the upload that best
+//! looks like the original recording wins instead of whatever search returns first.
+
+use crate::models::{SearchResult, SearchResultSnippet, TubeTrack};
+#[cfg(test)]
+use crate::models::test_track;
+use crate::normalize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const TITLE_WEIGHT: f64 = 0.5;
+const ARTIST_IN_CHANNEL_WEIGHT: f64 = 0.25;
+const ARTIST_IN_TITLE_WEIGHT: f64 = 0.15;
+const OFFICIAL_CHANNEL_WEIGHT: f64 = 0.15;
+const OFFICIAL_TITLE_WEIGHT: f64 = 0.1;
+const PENALTY: f64 = 0.35;
+const DURATION_BONUS: f64 = 0.1;
+const DURATION_PENALTY: f64 = 0.3;
+
+/// Words that usually mean the upload is not the original recording. A word
+/// is only penalized when the track title itself does not contain it.
+const PENALTY_WORDS: &[&str] = &[
+    "cover",
+    "live",
+    "karaoke",
+    "lyrics",
+    "lyric",
+    "reaction",
+    "instrumental",
+    "remix",
+    "nightcore",
+    "8d",
+    "sped up",
+    "slowed",
+    "tutorial",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoMatch {
+    pub video_id: String,
+    pub title: String,
+    pub channel_title: String,
+    /// How confident we are that this video is the track, from 0.0 to 1.0.
+    pub confidence: f64,
+}
+
+/// Lowercases, folds diacritics and replaces punctuation with spaces, padded
+/// with a space on each side so whole words and phrases can be found with `contains`.
+fn normalize(text: &str) -> String {
+    format!(" {} ", normalize::words(text).join(" "))
+}
+
+fn compact(text: &str) -> String {
+    normalize(text).replace(' ', "")
+}
+
+fn contains_word(haystack: &str, word: &str) -> bool {
+    haystack.contains(&format!(" {} ", word))
+}
+
+/// Scores how well a search result matches a track.
+pub fn score(track: &TubeTrack, snippet: &SearchResultSnippet) -> f64 {
+    let track_title = normalize(&track.title);
+    let video_title = normalize(&snippet.title);
+    let channel = snippet.channel_title.to_lowercase();
+    let artist = compact(&track.artist);
+
+    let title_words: Vec<&str> = track_title.split_whitespace().collect();
+    let mut score = if title_words.is_empty() {
+        0.0
+    } else {
+        let found = title_words
+            .iter()
+            .filter(|word| contains_word(&video_title, word))
+            .count();
+        TITLE_WEIGHT * found as f64 / title_words.len() as f64
+    };
+
+    let artist_in_channel = !artist.is_empty() && compact(&channel).contains(&artist);
+    if artist_in_channel {
+        score += ARTIST_IN_CHANNEL_WEIGHT;
+    } else if !artist.is_empty() && compact(&snippet.title).contains(&artist) {
+        score += ARTIST_IN_TITLE_WEIGHT;
+    }
+
+    let official_channel = channel.ends_with("- topic")
+        || channel.contains("vevo")
+        || contains_word(&normalize(&channel), "official")
+        || (!artist.is_empty() && compact(&channel) == artist);
+    if official_channel {
+        score += OFFICIAL_CHANNEL_WEIGHT;
+    }
+
+    if contains_word(&video_title, "official") {
+        score += OFFICIAL_TITLE_WEIGHT;
+    }
+
+    for word in PENALTY_WORDS {
+        let penalized = contains_word(&video_title, word) || contains_word(&normalize(&channel), word);
+        if penalized && !contains_word(&track_title, word) {
+            score -= PENALTY;
+        }
+    }
+
+    score.clamp(0.0, 1.0)
+}
+
+/// Rewards videos about as long as the track and penalizes extended mixes,
+/// previews and the like. Unknown lengths on either side leave the score alone.
+pub fn duration_adjustment(track: &TubeTrack, video_duration: Option<Duration>, tolerance: Duration) -> f64 {
+    match (track.duration_secs, video_duration) {
+        (Some(track_secs), Some(video_duration)) if track_secs > 0 && !video_duration.is_zero() => {
+            let difference = track_secs.abs_diff(video_duration.as_secs());
+            if difference <= tolerance.as_secs() {
+                DURATION_BONUS
+            } else {
+                -DURATION_PENALTY
+            }
+        }
+        _ => 0.0,
+    }
+}
+
+/// Picks the highest scoring result. Ties go to the result search ranked first.
+/// `durations` maps video ids to their lengths and may be empty.
+pub fn best_match(
+    track: &TubeTrack,
+    results: &[SearchResult],
+    durations: &HashMap<String, Duration>,
+    tolerance: Duration,
+) -> Option<VideoMatch> {
+    let mut best: Option<VideoMatch> = None;
+    for result in results {
+        let video_id = result.id.clone().into_inner();
+        let adjustment = duration_adjustment(track, durations.get(&video_id).copied(), tolerance);
+        let confidence = (score(track, &result.snippet) + adjustment).clamp(0.0, 1.0);
+        let is_better = match &best {
+            Some(best) => confidence > best.confidence,
+            None => true,
+        };
+        if is_better {
+            best = Some(VideoMatch {
+                video_id,
+                title: result.snippet.title.clone(),
+                channel_title: result.snippet.channel_title.clone(),
+                confidence,
+            });
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+fn snippet(title: &str, channel_title: &str) -> SearchResultSnippet {
+    SearchResultSnippet {
+        title: title.to_string(),
+        channel_title: channel_title.to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_score_prefers_official_uploads() {
+    let track = test_track("id", "Shape of You", "Ed Sheeran");
+    let official = score(&track, &snippet("Ed Sheeran - Shape of You (Official Music Video)", "Ed Sheeran"));
+    let topic = score(&track, &snippet("Shape of You", "Ed Sheeran - Topic"));
+    let karaoke = score(&track, &snippet("Shape of You - Ed Sheeran (Karaoke Version)", "Sing King"));
+    let cover = score(&track, &snippet("Shape of You | Ed Sheeran cover", "Some Busker"));
+
+    assert!(official > 0.9);
+    assert!(topic > 0.8);
+    assert!(karaoke < 0.5);
+    assert!(cover < 0.5);
+}
+
+#[test]
+fn test_score_ignores_penalty_words_in_track_title() {
+    let track = test_track("id", "Live Forever", "Oasis");
+    let confidence = score(&track, &snippet("Oasis - Live Forever (Official Video)", "Oasis"));
+    assert!(confidence > 0.9);
+}
+
+#[test]
+fn test_duration_adjustment() {
+    let mut track = test_track("id", "Interstellar", "Deep Forest & Gaudi");
+    let tolerance = Duration::from_secs(20);
+    assert_eq!(duration_adjustment(&track, Some(Duration::from_secs(290)), tolerance), 0.0);
+
+    track.duration_secs = Some(290);
+    assert!(duration_adjustment(&track, Some(Duration::from_secs(301)), tolerance) > 0.0);
+    assert!(duration_adjustment(&track, Some(Duration::from_secs(598)), tolerance) < 0.0);
+    assert!(duration_adjustment(&track, Some(Duration::from_secs(30)), tolerance) < 0.0);
+    assert_eq!(duration_adjustment(&track, None, tolerance), 0.0);
+}
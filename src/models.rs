@@ -1,6 +1,8 @@
 
+use duration_string::DurationString;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 //
 // Search
 //
@@ -8,16 +10,28 @@ use std::collections::HashMap;
 pub struct SearchRequestBuilder {
     pub query: Option<String>,
     pub channel_id: Option<String>,
+    pub max_results: Option<u64>,
+    pub filters: SearchFilters,
 }
 
 impl SearchRequestBuilder {
     pub(crate) fn build<S: Into<String>>(self, api_key: S) -> SearchRequest {
+        let filters = self.filters;
         SearchRequest {
             part: String::from("snippet"),
             key: api_key.into(),
             query: self.query,
             _type: Some(String::from("video")),
-            max_results: Some(1),
+            max_results: Some(self.max_results.unwrap_or(1)),
+            channel_id: self.channel_id.or(filters.channel_id),
+            video_category_id: filters.video_category_id,
+            region_code: filters.region_code,
+            relevance_language: filters.relevance_language,
+            safe_search: filters.safe_search,
+            // YouTube only knows `true` and `any`.
+            video_embeddable: filters
+                .video_embeddable
+                .map(|embeddable| String::from(if embeddable { "true" } else { "any" })),
         }
     }
 }
@@ -32,6 +46,49 @@ pub struct SearchRequest {
     #[serde(rename = "type")]
     _type: Option<String>,
     max_results: Option<u64>,
+    channel_id: Option<String>,
+    video_category_id: Option<String>,
+    region_code: Option<String>,
+    relevance_language: Option<String>,
+    safe_search: Option<SafeSearch>,
+    video_embeddable: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeSearch {
+    Moderate,
+    None,
+    Strict,
+}
+
+/// Search parameters that narrow down which videos can match a track, e.g.
+/// `videoCategoryId` 10 for music.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchFilters {
+    pub channel_id: Option<String>,
+    pub video_category_id: Option<String>,
+    /// ISO 3166-1 alpha-2 country code, e.g. `GB`.
+    pub region_code: Option<String>,
+    /// ISO 639-1 language code, e.g. `en`.
+    pub relevance_language: Option<String>,
+    pub safe_search: Option<SafeSearch>,
+    pub video_embeddable: Option<bool>,
+}
+
+impl SearchFilters {
+    /// These filters, with the ones set in `overrides` taking their place.
+    pub fn with_overrides(&self, overrides: &SearchFilters) -> SearchFilters {
+        SearchFilters {
+            channel_id: overrides.channel_id.clone().or_else(|| self.channel_id.clone()),
+            video_category_id: overrides.video_category_id.clone().or_else(|| self.video_category_id.clone()),
+            region_code: overrides.region_code.clone().or_else(|| self.region_code.clone()),
+            relevance_language: overrides.relevance_language.clone().or_else(|| self.relevance_language.clone()),
+            safe_search: overrides.safe_search.or(self.safe_search),
+            video_embeddable: overrides.video_embeddable.or(self.video_embeddable),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -112,6 +169,51 @@ impl Id {
     }
 }
 
+//
+// Videos
+//
+pub type VideoListResponse = Response<Video>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Video {
+    pub kind: String,
+    pub etag: String,
+    pub id: String,
+    pub content_details: Option<VideoContentDetails>,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoContentDetails {
+    /// The length of the video as an ISO-8601 duration, e.g. `PT4M13S`.
+    pub duration: Option<String>,
+    pub dimension: Option<String>,
+    pub definition: Option<String>,
+    pub caption: Option<String>,
+    pub licensed_content: Option<bool>,
+}
+
+impl VideoContentDetails {
+    /// The parsed video length. YouTube only uses days, hours, minutes and
+    /// seconds, so `P1DT2H3M4S` becomes `1d2h3m4s` for `DurationString`.
+    pub fn duration(&self) -> Option<Duration> {
+        let iso = self.duration.as_ref()?.strip_prefix('P')?;
+        let (date, time) = match iso.split_once('T') {
+            Some((date, time)) => (date, time),
+            None => (iso, ""),
+        };
+        if date.contains(['Y', 'M']) {
+            return None;
+        }
+        let duration = format!("{}{}", date, time).to_lowercase();
+        if duration.is_empty() {
+            return None;
+        }
+        DurationString::from_string(duration).ok().map(Duration::from)
+    }
+}
+
 //
 // Playlist
 //
@@ -127,6 +229,27 @@ pub struct PlaylistSnippet {
     pub localized: Option<PlaylistLocalization>,
 }
 
+/// Who can see a playlist.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistPrivacy {
+    #[default]
+    Private,
+    Unlisted,
+    Public,
+}
+
+impl PlaylistPrivacy {
+    /// The value of `status.privacyStatus`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaylistPrivacy::Private => "private",
+            PlaylistPrivacy::Unlisted => "unlisted",
+            PlaylistPrivacy::Public => "public",
+        }
+    }
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistStatus {
@@ -156,10 +279,13 @@ pub struct PlaylistResponse {
     // pub kind: String,
     //pub etag: String,
     pub id: String,
-    // pub snippet: Option<PlaylistSnippet>,
+    pub snippet: Option<PlaylistSnippet>,
     // pub status: Option<PlaylistStatus>,
+    pub content_details: Option<PlaylistContentDetails>,
 }
 
+pub type PlaylistListResponse = Response<PlaylistResponse>;
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistContentDetails {
@@ -177,28 +303,76 @@ pub struct PlaylistPlayer {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistItem {
+    /// Only set when updating an existing item.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
     snippet: PlaylistItemSnippet,
 }
 
 impl PlaylistItem {
     pub fn new(playlist_id:String, video_id:String) -> PlaylistItem {
         PlaylistItem { 
+            id: None,
             snippet: PlaylistItemSnippet {
                 playlist_id: playlist_id,
                 resource_id: PlaylistItemResource {
                     kind: "youtube#video".to_string(),
                     video_id: video_id,
                 },
+                position: None,
             },
         }
     }
+
+    /// The zero-based position to insert or move the item to. Without one,
+    /// YouTube appends the item.
+    pub fn with_position(mut self, position: u32) -> PlaylistItem {
+        self.snippet.position = Some(position);
+        self
+    }
+
+    pub fn playlist_id(&self) -> &str {
+        &self.snippet.playlist_id
+    }
+
+    /// Targets an existing item, for playlistItems.update.
+    pub fn with_id(mut self, id: String) -> PlaylistItem {
+        self.id = Some(id);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistItemResponse {
+    pub id: String,
+    snippet: PlaylistItemSnippet,
+}
+
+impl PlaylistItemResponse {
+    pub fn playlist_id(&self) -> &str {
+        &self.snippet.playlist_id
+    }
+
+    pub fn video_id(&self) -> &str {
+        &self.snippet.resource_id.video_id
+    }
+
+    /// The zero-based position of the item in its playlist.
+    pub fn position(&self) -> Option<u32> {
+        self.snippet.position
+    }
 }
 
+pub type PlaylistItemListResponse = Response<PlaylistItemResponse>;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistItemSnippet {
     playlist_id: String,
     resource_id: PlaylistItemResource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<u32>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -220,6 +394,20 @@ pub struct GoogleError {
     errors: Vec<ErrorItem>,
     message: String,
 }
+impl GoogleError {
+    /// The reason of the first error item, or one derived from the status code.
+    pub fn reason(&self) -> ErrorReason {
+        match self.errors.first() {
+            Some(item) => ErrorReason::from(item.reason.as_str()),
+            None => ErrorReason::from_status(self.code),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
 impl std::fmt::Display for GoogleError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -235,11 +423,130 @@ pub struct ErrorItem {
     reason: String,
 }
 
+/// The `reason` of a Google API error item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorReason {
+    QuotaExceeded,
+    RateLimitExceeded,
+    UserRateLimitExceeded,
+    PlaylistItemsNotAccessible,
+    PlaylistNotFound,
+    PlaylistFull,
+    VideoNotFound,
+    BackendError,
+    InternalError,
+    AuthError,
+    Forbidden,
+    Other(String),
+}
+
+impl ErrorReason {
+    /// Used when the error body is missing or has no error items.
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            401 => ErrorReason::AuthError,
+            403 => ErrorReason::Forbidden,
+            429 => ErrorReason::RateLimitExceeded,
+            500 => ErrorReason::InternalError,
+            501..=599 => ErrorReason::BackendError,
+            _ => ErrorReason::Other(status.to_string()),
+        }
+    }
+
+    /// Whether the same request may succeed if tried again shortly.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ErrorReason::RateLimitExceeded
+                | ErrorReason::UserRateLimitExceeded
+                | ErrorReason::BackendError
+                | ErrorReason::InternalError
+        )
+    }
+}
+
+impl From<&str> for ErrorReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "quotaExceeded" | "dailyLimitExceeded" => ErrorReason::QuotaExceeded,
+            "rateLimitExceeded" => ErrorReason::RateLimitExceeded,
+            "userRateLimitExceeded" => ErrorReason::UserRateLimitExceeded,
+            "playlistItemsNotAccessible" => ErrorReason::PlaylistItemsNotAccessible,
+            "playlistNotFound" => ErrorReason::PlaylistNotFound,
+            "playlistContainsMaximumNumberOfVideos" => ErrorReason::PlaylistFull,
+            "videoNotFound" => ErrorReason::VideoNotFound,
+            "backendError" => ErrorReason::BackendError,
+            "internalError" => ErrorReason::InternalError,
+            "authError" => ErrorReason::AuthError,
+            "forbidden" => ErrorReason::Forbidden,
+            other => ErrorReason::Other(other.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TubeTrack {
     pub id: String,
     pub title: String,
     pub artist: String,
-    pub video_id: Option<String>
-}
\ No newline at end of file
+    pub video_id: Option<String>,
+    /// Length of the track in seconds, when the source knows it.
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+/// A track with no video or duration yet, the way tests mostly need one.
+#[cfg(test)]
+pub fn test_track(id: &str, title: &str, artist: &str) -> TubeTrack {
+    TubeTrack {
+        id: id.to_string(),
+        title: title.to_string(),
+        artist: artist.to_string(),
+        video_id: None,
+        duration_secs: None,
+    }
+}
+
+#[test]
+fn test_video_content_details_duration() {
+    let details = |duration: &str| VideoContentDetails {
+        duration: Some(duration.to_string()),
+        ..Default::default()
+    };
+    assert_eq!(details("PT4M13S").duration(), Some(Duration::from_secs(253)));
+    assert_eq!(details("PT1H2M").duration(), Some(Duration::from_secs(3720)));
+    assert_eq!(details("P1DT30S").duration(), Some(Duration::from_secs(86430)));
+    assert_eq!(details("P0D").duration(), Some(Duration::ZERO));
+    assert_eq!(details("garbage").duration(), None);
+}
+
+#[test]
+fn test_search_request_filters() {
+    let global = SearchFilters {
+        video_category_id: Some(String::from("10")),
+        region_code: Some(String::from("GB")),
+        video_embeddable: Some(true),
+        ..Default::default()
+    };
+    let filters = global.with_overrides(&SearchFilters {
+        region_code: Some(String::from("US")),
+        safe_search: Some(SafeSearch::Strict),
+        ..Default::default()
+    });
+    let request = SearchRequestBuilder {
+        query: Some(String::from("houdini dua lipa")),
+        channel_id: Some(String::from("UCdualipa")),
+        max_results: Some(5),
+        filters,
+    }
+    .build("key");
+
+    let value = serde_json::to_value(&request).unwrap();
+    assert_eq!(value["channelId"], "UCdualipa");
+    assert_eq!(value["videoCategoryId"], "10");
+    assert_eq!(value["regionCode"], "US");
+    assert_eq!(value["safeSearch"], "strict");
+    assert_eq!(value["videoEmbeddable"], "true");
+    assert_eq!(value["relevanceLanguage"], serde_json::Value::Null);
+}
@@ -0,0 +1,290 @@
+//! Cleans up Sonos track metadata before it is searched for.
+//!
+//! Titles lose noise such as "(Remastered 2011)", "- Single Version" or
+//! "[Explicit]" and featured artists are split off. `query_variants` then
+//! gives the queries to fall back through, most specific first, in the
+//! track's own spelling. Only comparisons fold diacritics, so "Beyoncé" and
+//! "Beyonce" compare equal.
+
+use regex::Regex;
+use std::sync::OnceLock;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Words inside brackets or after " - " that say nothing about the recording
+/// a video should have. Live, acoustic and remix versions are kept on purpose.
+const NOISE: &str = r"(?i)^(?:.*\b(?:remaster(?:ed)?|explicit|clean|mono|stereo|bonus track|deluxe|expanded|anniversary|edition)\b.*|(?:single|album|radio|original) (?:version|edit|mix)|radio edit|\d{4}|from .+)$";
+const FEATURING: &str = r"(?i)^(?:feat\.?|ft\.?|featuring|with)\s+(.+)$";
+const TRAILING_FEATURING: &str = r"(?i)\s+(?:feat\.?|ft\.?|featuring)\s+(.+)$";
+const BRACKETS: &str = r"\s*[(\[]([^)\]]*)[)\]]";
+const ARTIST_SEPARATORS: &str = r"(?i)\s*(?:,|;|&|\+|/|\bfeat\.|\bfeat\b|\bft\.|\bft\b|\bfeaturing\b|\bvs\.|\bvs\b)\s*";
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+fn noise() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    regex(&CELL, NOISE)
+}
+
+fn featuring() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    regex(&CELL, FEATURING)
+}
+
+fn trailing_featuring() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    regex(&CELL, TRAILING_FEATURING)
+}
+
+fn brackets() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    regex(&CELL, BRACKETS)
+}
+
+fn artist_separators() -> &'static Regex {
+    static CELL: OnceLock<Regex> = OnceLock::new();
+    regex(&CELL, ARTIST_SEPARATORS)
+}
+
+/// Decomposes compatibility characters and drops diacritics from Latin
+/// letters, so "Beyoncé" becomes "Beyonce" and "ﬁ" becomes "fi". Other
+/// scripts keep their marks: "ビートルズ" and "Йорк" stay as they are. Case is kept.
+pub fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    let mut after_latin = false;
+    for c in text.nfkd() {
+        if is_combining_mark(c) {
+            if !after_latin {
+                folded.push(c);
+            }
+            continue;
+        }
+        after_latin = is_latin(c);
+        match c {
+            'ß' => folded.push_str("ss"),
+            'æ' => folded.push_str("ae"),
+            'Æ' => folded.push_str("AE"),
+            'œ' => folded.push_str("oe"),
+            'Œ' => folded.push_str("OE"),
+            'ø' => folded.push('o'),
+            'Ø' => folded.push('O'),
+            'đ' => folded.push('d'),
+            'Đ' => folded.push('D'),
+            'ł' => folded.push('l'),
+            'Ł' => folded.push('L'),
+            c => folded.push(fold_punctuation(c)),
+        }
+    }
+    // Puts back together what decomposing took apart, such as Hangul syllables.
+    folded.nfc().collect()
+}
+
+/// Composes the text and straightens typographic quotes and dashes, leaving
+/// letters alone.
+fn tidy(text: &str) -> String {
+    text.nfc().map(fold_punctuation).collect()
+}
+
+fn fold_punctuation(c: char) -> char {
+    match c {
+        '‘' | '’' | '´' => '\'',
+        '“' | '”' => '"',
+        '‐' | '‑' | '–' | '—' => '-',
+        c => c,
+    }
+}
+
+fn is_latin(c: char) -> bool {
+    matches!(c, 'A'..='Z' | 'a'..='z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}')
+}
+
+/// The folded, lowercased words of a text, without punctuation.
+pub fn words(text: &str) -> Vec<String> {
+    fold(text)
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn split_artists(artists: &str) -> Vec<String> {
+    artist_separators()
+        .split(artists)
+        .map(collapse_whitespace)
+        .filter(|artist| !artist.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NormalizedTrack {
+    /// The title without noise or featured artists.
+    pub title: String,
+    /// The title without anything in brackets or after " - " at all.
+    pub bare_title: String,
+    /// The main artists, e.g. both of "Deep Forest & Gaudi".
+    pub artists: Vec<String>,
+    pub featured: Vec<String>,
+}
+
+impl NormalizedTrack {
+    pub fn new(artist: &str, title: &str) -> Self {
+        let title = tidy(title);
+        let artist = tidy(artist);
+        let mut featured = Vec::new();
+
+        // Brackets with noise or featured artists go, the rest stays.
+        let mut cleaned = brackets()
+            .replace_all(&title, |caps: &regex::Captures| {
+                let inner = caps[1].trim();
+                if let Some(feat) = featuring().captures(inner) {
+                    featured.extend(split_artists(&feat[1]));
+                    String::new()
+                } else if noise().is_match(inner) {
+                    String::new()
+                } else {
+                    caps[0].to_string()
+                }
+            })
+            .to_string();
+
+        // "Title - Single Version", "Title - 2011 Remaster"
+        if let Some((head, tail)) = cleaned.rsplit_once(" - ") {
+            let tail = tail.trim();
+            if let Some(feat) = featuring().captures(tail) {
+                featured.extend(split_artists(&feat[1]));
+                cleaned = head.to_string();
+            } else if noise().is_match(tail) {
+                cleaned = head.to_string();
+            }
+        }
+
+        if let Some(feat) = trailing_featuring().captures(&cleaned.clone()) {
+            featured.extend(split_artists(&feat[1]));
+            cleaned.truncate(feat.get(0).unwrap().start());
+        }
+
+        let (main, featured_in_artist) = match trailing_featuring().captures(&artist) {
+            Some(feat) => (&artist[..feat.get(0).unwrap().start()], split_artists(&feat[1])),
+            None => (artist.as_str(), Vec::new()),
+        };
+        for artist in featured_in_artist {
+            if !featured.contains(&artist) {
+                featured.push(artist);
+            }
+        }
+
+        let title = collapse_whitespace(&cleaned);
+        let bare = brackets().replace_all(&title, "");
+        let bare = bare.split(" - ").next().unwrap_or_default();
+        let bare_title = match collapse_whitespace(bare) {
+            bare if bare.is_empty() => title.clone(),
+            bare => bare,
+        };
+
+        NormalizedTrack {
+            title,
+            bare_title,
+            artists: split_artists(main),
+            featured,
+        }
+    }
+
+    /// Search queries to try in order, without duplicates: the cleaned title
+    /// with all main artists, then the bare title with them, then the bare
+    /// title with just the first artist.
+    pub fn query_variants(&self) -> Vec<QueryVariant> {
+        let all_artists = self.artists.join(" ");
+        let first_artist = self.artists.first().cloned().unwrap_or_default();
+        let candidates = [
+            (&self.title, &all_artists),
+            (&self.bare_title, &all_artists),
+            (&self.bare_title, &first_artist),
+        ];
+
+        let mut variants: Vec<QueryVariant> = Vec::new();
+        for (title, artists) in candidates {
+            let query = collapse_whitespace(&format!("{} {}", title, artists));
+            if !query.is_empty() && !variants.iter().any(|v| v.query.eq_ignore_ascii_case(&query)) {
+                variants.push(QueryVariant {
+                    query,
+                    title: title.clone(),
+                });
+            }
+        }
+        variants
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryVariant {
+    pub query: String,
+    /// The title search results are ranked against.
+    pub title: String,
+}
+
+#[test]
+fn test_normalize_track() {
+    let track = NormalizedTrack::new("The Beatles", "Here Comes The Sun (Remastered 2009)");
+    assert_eq!(track.title, "Here Comes The Sun");
+
+    let track = NormalizedTrack::new("Queen", "Bohemian Rhapsody - Remastered 2011");
+    assert_eq!(track.title, "Bohemian Rhapsody");
+
+    let track = NormalizedTrack::new("Madonna", "Frozen - Single Version [Explicit]");
+    assert_eq!(track.title, "Frozen");
+
+    let track = NormalizedTrack::new("Calvin Harris", "One Kiss (feat. Dua Lipa)");
+    assert_eq!(track.title, "One Kiss");
+    assert_eq!(track.featured, vec!["Dua Lipa"]);
+
+    let track = NormalizedTrack::new("Ed Sheeran feat. Justin Bieber", "I Don't Care");
+    assert_eq!(track.artists, vec!["Ed Sheeran"]);
+    assert_eq!(track.featured, vec!["Justin Bieber"]);
+
+    let track = NormalizedTrack::new("Deep Forest & Gaudi", "Interstellar (Brazil/France)");
+    assert_eq!(track.title, "Interstellar (Brazil/France)");
+    assert_eq!(track.bare_title, "Interstellar");
+    assert_eq!(track.artists, vec!["Deep Forest", "Gaudi"]);
+    let queries: Vec<String> = track.query_variants().into_iter().map(|v| v.query).collect();
+    assert_eq!(
+        queries,
+        vec![
+            "Interstellar (Brazil/France) Deep Forest Gaudi",
+            "Interstellar Deep Forest Gaudi",
+            "Interstellar Deep Forest",
+        ]
+    );
+    assert_eq!(track.query_variants()[2].title, "Interstellar");
+
+    // Versions that are different recordings are kept.
+    let track = NormalizedTrack::new("Ed Sheeran", "Shape of You (Acoustic)");
+    assert_eq!(track.title, "Shape of You (Acoustic)");
+
+    // Queries keep the track's own spelling.
+    let track = NormalizedTrack::new("Beyoncé", "Déjà Vu – Single Version");
+    let queries: Vec<String> = track.query_variants().into_iter().map(|v| v.query).collect();
+    assert_eq!(queries, vec!["Déjà Vu Beyoncé"]);
+
+    let track = NormalizedTrack::new("ビートルズ", "イエスタデイ (Remastered 2009)");
+    let queries: Vec<String> = track.query_variants().into_iter().map(|v| v.query).collect();
+    assert_eq!(queries, vec!["イエスタデイ ビートルズ"]);
+}
+
+#[test]
+fn test_words() {
+    assert_eq!(words("Beyoncé – Crazy in Love"), vec!["beyonce", "crazy", "in", "love"]);
+    assert_eq!(words("Straße"), vec!["strasse"]);
+    assert_eq!(fold("Motörhead’s ﬁnest"), "Motorhead's finest");
+    // Marks that are part of other scripts stay.
+    assert_eq!(words("ビートルズ"), vec!["ビートルズ"]);
+    assert_eq!(words("ﾋﾞｰﾄﾙｽﾞ"), vec!["ビートルズ"]);
+    assert_eq!(words("Нью-Йорк"), vec!["нью", "йорк"]);
+    assert_eq!(words("방탄소년단"), vec!["방탄소년단"]);
+}
@@ -0,0 +1,250 @@
+//! Pins tracks to a video, or to no video at all, when matching keeps
+//! getting them wrong.
+//!
+//! The file lives in the home directory next to the config and is picked up
+//! again when edited by hand while sonotube runs. A track is looked up by its
+//! Sonos URI first, then by its normalized artist and title:
+//!
+//! ```json
+//! {
+//!   "uris": { "x-sonos-spotify:spotify%3atrack%3a7qiZfU4dY1lWllzX7mPBI3": "JGwWNGJdvx8" },
+//!   "tracks": { "Deep Forest & Gaudi|Interstellar": "skip" }
+//! }
+//! ```
+
+use crate::json_file;
+use crate::match_cache::MatchCache;
+use crate::models::TubeTrack;
+#[cfg(test)]
+use crate::models::test_track;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+pub const OVERRIDES: &str = ".sonotube_overrides.json";
+const SKIP: &str = "skip";
+
+pub type SharedOverrides = Arc<Mutex<Overrides>>;
+
+/// What to add for a track instead of searching. Written as the video id or `"skip"`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Override {
+    Video(String),
+    Skip,
+}
+
+impl From<String> for Override {
+    fn from(value: String) -> Self {
+        if value.eq_ignore_ascii_case(SKIP) {
+            Override::Skip
+        } else {
+            Override::Video(value)
+        }
+    }
+}
+
+impl From<Override> for String {
+    fn from(value: Override) -> Self {
+        match value {
+            Override::Video(video_id) => video_id,
+            Override::Skip => String::from(SKIP),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OverrideEntries {
+    #[serde(default)]
+    pub uris: HashMap<String, Override>,
+    /// Keyed by `artist|title`.
+    #[serde(default)]
+    pub tracks: HashMap<String, Override>,
+}
+
+#[derive(Debug, Default)]
+pub struct Overrides {
+    path: Option<PathBuf>,
+    /// When the file was last read, to notice edits by hand.
+    loaded_at: Option<SystemTime>,
+    entries: OverrideEntries,
+}
+
+impl Overrides {
+    /// Loads the overrides file of this name in the home directory.
+    pub fn load(file_name: &str) -> Self {
+        Overrides::load_path(json_file::home_path(file_name))
+    }
+
+    /// Loads the overrides file at `path`.
+    pub fn load_path(path: PathBuf) -> Self {
+        let mut overrides = Overrides {
+            path: Some(path),
+            ..Default::default()
+        };
+        overrides.read();
+        info!(
+            "Loaded {} track overrides",
+            overrides.entries.uris.len() + overrides.entries.tracks.len()
+        );
+        overrides
+    }
+
+    /// Overrides that are never written to disk.
+    pub fn in_memory() -> Self {
+        Overrides::default()
+    }
+
+    pub fn shared(self) -> SharedOverrides {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// The override for a track, by URI or else by artist and title.
+    pub fn get(&mut self, track: &TubeTrack) -> Option<Override> {
+        self.reload_if_changed();
+        self.entries
+            .uris
+            .get(&track.id)
+            .or_else(|| self.entries.tracks.get(&MatchCache::key(&track.artist, &track.title)))
+            .cloned()
+    }
+
+    pub fn entries(&mut self) -> &OverrideEntries {
+        self.reload_if_changed();
+        &self.entries
+    }
+
+    /// Sets an override. It holds for this run even if saving it fails.
+    pub fn set_uri(&mut self, uri: &str, value: Override) -> io::Result<()> {
+        self.reload_if_changed();
+        self.entries.uris.insert(uri.to_string(), value);
+        self.save()
+    }
+
+    /// Sets an override. It holds for this run even if saving it fails.
+    pub fn set_track(&mut self, artist: &str, title: &str, value: Override) -> io::Result<()> {
+        self.reload_if_changed();
+        self.entries.tracks.insert(MatchCache::key(artist, title), value);
+        self.save()
+    }
+
+    /// Removes an override. Returns whether there was one.
+    pub fn remove_uri(&mut self, uri: &str) -> io::Result<bool> {
+        self.reload_if_changed();
+        let removed = self.entries.uris.remove(uri).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Removes an override. Returns whether there was one.
+    pub fn remove_track(&mut self, artist: &str, title: &str) -> io::Result<bool> {
+        self.reload_if_changed();
+        let removed = self.entries.tracks.remove(&MatchCache::key(artist, title)).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn read(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        self.loaded_at = Some(SystemTime::now());
+        // A file that cannot be read leaves the overrides we had.
+        let entries: OverrideEntries = match json_file::load(path, "overrides") {
+            Some(entries) => entries,
+            None => return,
+        };
+        // Keys written by hand need not be normalized.
+        let tracks = entries
+            .tracks
+            .into_iter()
+            .map(|(key, value)| match key.split_once('|') {
+                Some((artist, title)) => (MatchCache::key(artist, title), value),
+                None => (key, value),
+            })
+            .collect();
+        self.entries = OverrideEntries {
+            uris: entries.uris,
+            tracks,
+        };
+    }
+
+    fn reload_if_changed(&mut self) {
+        let (path, loaded_at) = match (&self.path, self.loaded_at) {
+            (Some(path), Some(loaded_at)) => (path, loaded_at),
+            _ => return,
+        };
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified());
+        if matches!(modified, Ok(modified) if modified > loaded_at) {
+            info!("Reloading track overrides");
+            self.read();
+        }
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        json_file::save_pretty(path, &self.entries)?;
+        self.loaded_at = Some(SystemTime::now());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_load_save_overrides() {
+    let test_file_name = ".test_sonotube_overrides.json";
+    std::fs::write(
+        json_file::home_path(test_file_name),
+        r#"{ "uris": { "uri:1": "JGwWNGJdvx8" }, "tracks": { "Deep Forest & Gaudi|Interstellar": "SKIP" } }"#,
+    )
+    .unwrap();
+
+    let mut overrides = Overrides::load(test_file_name);
+    assert_eq!(
+        overrides.get(&test_track("uri:1", "Shape of You", "Ed Sheeran")),
+        Some(Override::Video(String::from("JGwWNGJdvx8")))
+    );
+    assert_eq!(
+        overrides.get(&test_track("uri:2", "INTERSTELLAR", "deep forest gaudi")),
+        Some(Override::Skip)
+    );
+    assert_eq!(overrides.get(&test_track("uri:3", "Houdini", "Dua Lipa")), None);
+
+    overrides
+        .set_track("Dua Lipa", "Houdini", Override::Video(String::from("suAR1PYFNYA")))
+        .unwrap();
+    assert!(overrides.remove_uri("uri:1").unwrap());
+    assert!(!overrides.remove_uri("uri:1").unwrap());
+
+    let mut loaded = Overrides::load(test_file_name);
+    assert_eq!(
+        loaded.get(&test_track("uri:3", "Houdini", "Dua Lipa")),
+        Some(Override::Video(String::from("suAR1PYFNYA")))
+    );
+    assert_eq!(loaded.get(&test_track("uri:1", "Shape of You", "Ed Sheeran")), None);
+}
+
+#[test]
+fn test_overrides_survive_io_errors() {
+    // A directory where the file should be can be neither read nor written.
+    let dir = std::env::temp_dir().join(format!("sonotube_overrides_dir_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut overrides = Overrides::load_path(dir.clone());
+    assert!(overrides.entries().uris.is_empty());
+    assert!(overrides.set_uri("uri:1", Override::Skip).is_err());
+    assert_eq!(overrides.entries().uris.get("uri:1"), Some(&Override::Skip));
+    std::fs::remove_dir_all(dir).unwrap();
+}
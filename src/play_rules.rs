@@ -0,0 +1,50 @@
+//! How long a track has to be listened to before sonotube adds it to YouTube.
+
+use std::time::Duration;
+
+/// When a track counts as played rather than skipped. The default counts
+/// every track as played at once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlayRules {
+    pub min_listened: Duration,
+    /// Shorter tracks never count as played.
+    pub min_track_length: Duration,
+    /// The share of a track, from 0 to 1, to listen to.
+    pub share: f64,
+    /// Listening this long is enough, however long the track.
+    pub enough: Duration,
+}
+
+impl PlayRules {
+    /// Whether `listened` of a track `length` long counts as a play. Streams
+    /// have no length, so only the minimum listened time applies to them.
+    pub fn qualifies(&self, length: Duration, listened: Duration) -> bool {
+        if length.is_zero() {
+            return listened >= self.min_listened;
+        }
+        if length < self.min_track_length {
+            return false;
+        }
+        let required = length.mul_f64(self.share).min(self.enough).max(self.min_listened);
+        listened >= required
+    }
+}
+
+#[test]
+fn test_play_rules() {
+    let rules = crate::config::Config::default().play_rules();
+    let secs = Duration::from_secs;
+    // Half the track, or 4 minutes of a long one.
+    assert!(!rules.qualifies(secs(180), secs(89)));
+    assert!(rules.qualifies(secs(180), secs(90)));
+    assert!(!rules.qualifies(secs(600), secs(239)));
+    assert!(rules.qualifies(secs(600), secs(240)));
+    // Short tracks never count, and the minimum listened time applies to the rest.
+    assert!(!rules.qualifies(secs(29), secs(29)));
+    assert!(!rules.qualifies(secs(40), secs(29)));
+    assert!(rules.qualifies(secs(40), secs(30)));
+    // Streams have no length.
+    assert!(!rules.qualifies(Duration::ZERO, secs(29)));
+    assert!(rules.qualifies(Duration::ZERO, secs(30)));
+    assert!(PlayRules::default().qualifies(secs(180), Duration::ZERO));
+}
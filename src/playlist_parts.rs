@@ -0,0 +1,76 @@
+//! Remembers the parts of a playlist that outgrew YouTube's item limit, so a
+//! restart carries on in the latest part and dedup still covers all of them.
+//!
+//! Parts are keyed by the id of the first playlist, the one sonotube was
+//! pointed at or created.
+
+use crate::json_file;
+use log::{info, warn};
+use std::collections::HashMap;
+
+pub const PLAYLIST_PARTS: &str = ".sonotube_playlist_parts.json";
+
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistParts {
+    file_name: Option<String>,
+    /// The ids of the later parts of each playlist, in order.
+    entries: HashMap<String, Vec<String>>,
+}
+
+impl PlaylistParts {
+    pub fn load(file_name: &str) -> Self {
+        let entries: HashMap<String, Vec<String>> =
+            json_file::load(&json_file::cache_path(file_name), "playlist parts").unwrap_or_default();
+        info!("Loaded parts for {} playlists", entries.len());
+        PlaylistParts {
+            file_name: Some(file_name.to_string()),
+            entries,
+        }
+    }
+
+    /// An index that is never written to disk, for playlists that do not
+    /// outlive the process.
+    pub fn in_memory() -> Self {
+        PlaylistParts::default()
+    }
+
+    /// All parts of the playlist starting at `first`, oldest first.
+    pub fn parts(&self, first: &str) -> Vec<String> {
+        let mut parts = vec![first.to_string()];
+        if let Some(later) = self.entries.get(first) {
+            parts.extend(later.iter().cloned());
+        }
+        parts
+    }
+
+    pub fn add_part(&mut self, first: &str, part: &str) {
+        self.entries
+            .entry(first.to_string())
+            .or_default()
+            .push(part.to_string());
+        self.save();
+    }
+
+    fn save(&self) {
+        let file_name = match &self.file_name {
+            Some(file_name) => file_name,
+            None => return,
+        };
+        if let Err(e) = json_file::save(&json_file::cache_path(file_name), &self.entries) {
+            warn!("Unable to save the playlist parts: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_load_save_playlist_parts() {
+    let test_file_name = ".test_playlist_parts.json";
+    let mut parts = PlaylistParts::load(test_file_name);
+    parts.entries.clear();
+    parts.add_part("PL1", "PL2");
+    parts.add_part("PL1", "PL3");
+
+    let loaded = PlaylistParts::load(test_file_name);
+    assert_eq!(loaded.parts("PL1"), vec!["PL1", "PL2", "PL3"]);
+    assert_eq!(loaded.parts("PL4"), vec!["PL4"]);
+}
@@ -0,0 +1,238 @@
+//! Keeps track of how much of the daily YouTube Data API quota we have spent.
+//!
+//! Google resets the quota at midnight Pacific time, so the ledger does too.
+//! The ledger is saved to the cache directory after every call so restarts
+//! do not forget what was already spent today.
+
+use crate::json_file;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const QUOTA_LEDGER: &str = ".sonotube_quota.json";
+
+pub type SharedQuotaLedger = Arc<Mutex<QuotaLedger>>;
+
+/// The YouTube Data API calls `Tube` makes, with their quota cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiCall {
+    Search,
+    VideosList,
+    PlaylistsList,
+    PlaylistsInsert,
+    PlaylistItemsList,
+    PlaylistItemsInsert,
+    PlaylistItemsUpdate,
+    PlaylistItemsDelete,
+}
+
+impl ApiCall {
+    pub fn cost(&self) -> u64 {
+        match self {
+            ApiCall::Search => 100,
+            ApiCall::VideosList | ApiCall::PlaylistsList | ApiCall::PlaylistItemsList => 1,
+            ApiCall::PlaylistsInsert
+            | ApiCall::PlaylistItemsInsert
+            | ApiCall::PlaylistItemsUpdate
+            | ApiCall::PlaylistItemsDelete => 50,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApiCall::Search => "search.list",
+            ApiCall::VideosList => "videos.list",
+            ApiCall::PlaylistsList => "playlists.list",
+            ApiCall::PlaylistsInsert => "playlists.insert",
+            ApiCall::PlaylistItemsList => "playlistItems.list",
+            ApiCall::PlaylistItemsInsert => "playlistItems.insert",
+            ApiCall::PlaylistItemsUpdate => "playlistItems.update",
+            ApiCall::PlaylistItemsDelete => "playlistItems.delete",
+        }
+    }
+}
+
+/// Whether work can wait for tomorrow's quota. Deferrable work stops once
+/// the reserve is reached, urgent work only when the budget is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Urgent,
+    Deferrable,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaUsage {
+    /// The Pacific date this usage belongs to, e.g. `2024-03-10`.
+    pub day: String,
+    pub used: u64,
+    pub budget: u64,
+    pub remaining: u64,
+    pub calls: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct LedgerEntries {
+    day: String,
+    used: u64,
+    calls: HashMap<String, u64>,
+}
+
+#[derive(Debug)]
+pub struct QuotaLedger {
+    file_name: Option<String>,
+    budget: u64,
+    reserve: u64,
+    entries: LedgerEntries,
+}
+
+impl QuotaLedger {
+    /// Loads today's ledger from the cache directory. `reserve` is how many
+    /// units are kept back for urgent work.
+    pub fn load(file_name: &str, budget: u64, reserve: u64) -> Self {
+        let entries = json_file::load(&json_file::cache_path(file_name), "quota ledger").unwrap_or_default();
+        let mut ledger = QuotaLedger {
+            file_name: Some(file_name.to_string()),
+            budget,
+            reserve,
+            entries,
+        };
+        ledger.roll_over(Utc::now());
+        info!("Quota used today: {} of {} units", ledger.entries.used, ledger.budget);
+        ledger
+    }
+
+    /// A ledger that is never written to disk.
+    #[cfg(test)]
+    pub fn in_memory(budget: u64, reserve: u64) -> Self {
+        let mut ledger = QuotaLedger {
+            file_name: None,
+            budget,
+            reserve,
+            entries: LedgerEntries::default(),
+        };
+        ledger.roll_over(Utc::now());
+        ledger
+    }
+
+    pub fn shared(self) -> SharedQuotaLedger {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Starts a fresh day once midnight Pacific has passed.
+    fn roll_over(&mut self, now: DateTime<Utc>) {
+        let today = pacific_date(now).to_string();
+        if self.entries.day != today {
+            if !self.entries.day.is_empty() {
+                info!("Quota reset for {}. Used {} units on {}", today, self.entries.used, self.entries.day);
+            }
+            self.entries = LedgerEntries {
+                day: today,
+                ..Default::default()
+            };
+        }
+    }
+
+    /// Whether `cost` more units can be spent on work of the given priority.
+    pub fn allows(&mut self, cost: u64, priority: Priority) -> bool {
+        self.roll_over(Utc::now());
+        let limit = match priority {
+            Priority::Urgent => self.budget,
+            Priority::Deferrable => self.budget.saturating_sub(self.reserve),
+        };
+        self.entries.used + cost <= limit
+    }
+
+    pub fn record(&mut self, call: ApiCall) {
+        self.roll_over(Utc::now());
+        self.entries.used += call.cost();
+        *self.entries.calls.entry(call.name().to_string()).or_insert(0) += 1;
+        if self.entries.used >= self.budget {
+            warn!("YouTube quota budget of {} units used up for {}", self.budget, self.entries.day);
+        }
+        self.save();
+    }
+
+    /// Marks today's budget as spent, e.g. after Google reported `quotaExceeded`.
+    pub fn exhaust(&mut self) {
+        self.roll_over(Utc::now());
+        self.entries.used = self.entries.used.max(self.budget);
+        self.save();
+    }
+
+    pub fn usage(&mut self) -> QuotaUsage {
+        self.roll_over(Utc::now());
+        QuotaUsage {
+            day: self.entries.day.clone(),
+            used: self.entries.used,
+            budget: self.budget,
+            remaining: self.budget.saturating_sub(self.entries.used),
+            calls: self.entries.calls.clone(),
+        }
+    }
+
+    fn save(&self) {
+        let file_name = match &self.file_name {
+            Some(file_name) => file_name,
+            None => return,
+        };
+        if let Err(e) = json_file::save(&json_file::cache_path(file_name), &self.entries) {
+            warn!("Unable to save the quota ledger: {}", e);
+        }
+    }
+}
+
+/// The date in the US Pacific time zone. Daylight saving time runs from 2am
+/// on the second Sunday of March to 2am on the first Sunday of November.
+fn pacific_date(now: DateTime<Utc>) -> NaiveDate {
+    let year = now.year();
+    let nth_sunday = |month: u32, n: i64| {
+        let first = NaiveDate::from_ymd_opt(year, month, 1).unwrap();
+        let to_sunday = (7 - first.weekday().num_days_from_sunday() as i64) % 7;
+        first + chrono::Duration::days(to_sunday + 7 * (n - 1))
+    };
+    // 2am PST is 10:00 UTC, 2am PDT is 09:00 UTC.
+    let dst_start = Utc.from_utc_datetime(&nth_sunday(3, 2).and_hms_opt(10, 0, 0).unwrap());
+    let dst_end = Utc.from_utc_datetime(&nth_sunday(11, 1).and_hms_opt(9, 0, 0).unwrap());
+    let offset = if now >= dst_start && now < dst_end { -7 } else { -8 };
+    (now + chrono::Duration::hours(offset)).date_naive()
+}
+
+#[test]
+fn test_pacific_date() {
+    let utc = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+
+    // Winter: UTC-8
+    assert_eq!(pacific_date(utc("2024-01-15T07:59:00Z")), date("2024-01-14"));
+    assert_eq!(pacific_date(utc("2024-01-15T08:00:00Z")), date("2024-01-15"));
+    // Summer: UTC-7
+    assert_eq!(pacific_date(utc("2024-07-04T06:59:00Z")), date("2024-07-03"));
+    assert_eq!(pacific_date(utc("2024-07-04T07:00:00Z")), date("2024-07-04"));
+    // DST started on 2024-03-10 and ended on 2024-11-03.
+    assert_eq!(pacific_date(utc("2024-03-10T07:30:00Z")), date("2024-03-09"));
+    assert_eq!(pacific_date(utc("2024-03-11T07:30:00Z")), date("2024-03-11"));
+    assert_eq!(pacific_date(utc("2024-11-04T07:30:00Z")), date("2024-11-03"));
+}
+
+#[test]
+fn test_quota_ledger_budget() {
+    let mut ledger = QuotaLedger::in_memory(300, 100);
+    assert!(ledger.allows(ApiCall::Search.cost(), Priority::Deferrable));
+
+    ledger.record(ApiCall::Search);
+    ledger.record(ApiCall::PlaylistItemsInsert);
+    ledger.record(ApiCall::VideosList);
+    assert_eq!(ledger.usage().used, 151);
+    assert_eq!(ledger.usage().calls["search.list"], 1);
+
+    // 151 + 100 is over the deferrable limit of 200 but within the budget.
+    assert!(!ledger.allows(ApiCall::Search.cost(), Priority::Deferrable));
+    assert!(ledger.allows(ApiCall::Search.cost(), Priority::Urgent));
+
+    ledger.entries.day = String::from("1999-12-31");
+    assert_eq!(ledger.usage().used, 0);
+}
@@ -2,18 +2,26 @@ use async_std::task;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::{collections::HashMap, sync::Arc, fs::OpenOptions};
-use std::time::Duration;
-use log::info;
-use sonos::Track;
-use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use log::{info, trace, warn};
+use sonos::{Speaker, Track, TransportState};
+use tokio::sync::mpsc;
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 use chrono;
 use dirs;
 
 use crate::config::Config;
+use crate::play_rules::PlayRules;
+use crate::discovery::{self, DeviceChanges, DeviceRegistry, DiscoverySchedule};
+use crate::upnp::{
+    self, EventListener, SpeakerEvent, Subscriber, Subscription, ZoneGroup, AVTRANSPORT_EVENT_PATH,
+    TOPOLOGY_CONTROL_PATH, TOPOLOGY_EVENT_PATH,
+};
 
 const TRACK_CACHE: &str = ".sonotube_tracks.json";
+/// How long the monitor waits for an event before checking on everything else.
+const EVENT_WAIT: Duration = Duration::from_secs(1);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(remote = "Track")]
@@ -27,112 +35,615 @@ struct TrackDef {
     pub running_time: Duration,
 }
 
+/// When and where a track was played.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "StoredPlay")]
+struct PlayRecord {
+    at: i64,
+    /// The room that played it, the group coordinator's when grouped.
+    room: Option<String>,
+    /// Every room in the group at the time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rooms: Option<Vec<String>>,
+}
+
+/// Older track caches only have the time of each play.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredPlay {
+    At(i64),
+    Play {
+        at: i64,
+        room: Option<String>,
+        #[serde(default)]
+        rooms: Option<Vec<String>>,
+    },
+}
+
+impl From<StoredPlay> for PlayRecord {
+    fn from(play: StoredPlay) -> Self {
+        match play {
+            StoredPlay::At(at) => PlayRecord {
+                at,
+                room: None,
+                rooms: None,
+            },
+            StoredPlay::Play { at, room, rooms } => PlayRecord { at, room, rooms },
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct SerTrack {
     #[serde(with = "TrackDef")]
     track: Track,
-    play_history: Option<Vec<i64>>,
+    play_history: Option<Vec<PlayRecord>>,
+    /// Times it was skipped before it counted as played.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    skip_history: Option<Vec<PlayRecord>>,
+}
+
+impl SerTrack {
+    fn has_plays(&self) -> bool {
+        self.play_history.as_ref().is_some_and(|plays| !plays.is_empty())
+    }
 }
 
 impl Clone for SerTrack {
     fn clone(&self) -> Self {
         Self {
-            track: Track {
-                title: self.track.title.clone(),
-                artist: self.track.artist.clone(),
-                album: self.track.album.clone(),
-                queue_position: self.track.queue_position,
-                uri: self.track.uri.clone(),
-                duration: self.track.duration.clone(),
-                running_time: self.track.running_time.clone(),
-            },
+            track: clone_track(&self.track),
             play_history: self.play_history.clone(),
+            skip_history: self.skip_history.clone(),
         }
     }
 
     fn clone_from(&mut self, source: &Self) {
-        self.track = source.clone().track;
+        self.track = clone_track(&source.track);
         self.play_history = source.play_history.clone();
+        self.skip_history = source.skip_history.clone();
+    }
+}
+
+fn clone_track(track: &Track) -> Track {
+    Track {
+        title: track.title.clone(),
+        artist: track.artist.clone(),
+        album: track.album.clone(),
+        queue_position: track.queue_position,
+        uri: track.uri.clone(),
+        duration: track.duration,
+        running_time: track.running_time,
     }
 }
 
+/// A track sent on to YouTube and the room it played in, if known.
+pub struct Play {
+    pub track: Track,
+    pub room: Option<String>,
+}
+
 pub struct SonoTube {
     
 }
 
+/// What a zone is playing, as far as we know.
+#[derive(Debug, Clone)]
+struct DeviceState {
+    room: String,
+    track_uri: Option<String>,
+    transport_state: Option<TransportState>,
+    /// When the current track was first seen playing.
+    first_seen: Option<chrono::DateTime<chrono::Utc>>,
+    /// The current track, until it counts as played or gets skipped.
+    pending: Option<PendingPlay>,
+}
+
+/// A track that has not been listened to long enough to count as played yet.
+#[derive(Debug)]
+struct PendingPlay {
+    track: Track,
+    record: PlayRecord,
+    /// Time spent playing before `playing_since`.
+    listened: Duration,
+    playing_since: Option<Instant>,
+}
+
+impl Clone for PendingPlay {
+    fn clone(&self) -> Self {
+        Self {
+            track: clone_track(&self.track),
+            record: self.record.clone(),
+            listened: self.listened,
+            playing_since: self.playing_since,
+        }
+    }
+}
+
+impl PendingPlay {
+    fn listened(&self) -> Duration {
+        self.listened + self.playing_since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+/// Remembers every track played and passes new ones on to the tube monitor.
+struct TrackLog {
+    tracks: HashMap<String, SerTrack>,
+    /// Each coordinator zone's state, by its UUID.
+    devices: HashMap<String, DeviceState>,
+    /// The household's zone groups; empty until a speaker told us.
+    groups: Vec<ZoneGroup>,
+    changed: bool,
+    sender: mpsc::UnboundedSender<Play>,
+    config: Config,
+    rules: PlayRules,
+}
+
+impl TrackLog {
+    fn new(tracks: HashMap<String, SerTrack>, sender: mpsc::UnboundedSender<Play>, config: Config) -> Self {
+        TrackLog {
+            tracks,
+            devices: HashMap::new(),
+            groups: Vec::new(),
+            changed: false,
+            sender,
+            rules: config.play_rules(),
+            config,
+        }
+    }
+
+    fn device(&mut self, device: &Speaker) -> &mut DeviceState {
+        self.devices.entry(device.uuid.clone()).or_insert_with(|| DeviceState {
+            room: device.name.clone(),
+            track_uri: None,
+            transport_state: None,
+            first_seen: None,
+            pending: None,
+        })
+    }
+
+    fn group(&self, uuid: &str) -> Option<&ZoneGroup> {
+        self.groups
+            .iter()
+            .find(|group| group.members.iter().any(|member| member.uuid == uuid))
+    }
+
+    /// Whether a zone plays for itself. Grouped members report their
+    /// coordinator's track, which would count every play once per room.
+    fn is_coordinator(&self, uuid: &str) -> bool {
+        self.group(uuid).is_none_or(|group| group.coordinator == uuid)
+    }
+
+    fn groups_changed(&mut self, groups: Vec<ZoneGroup>) {
+        if groups == self.groups {
+            return;
+        }
+        let rooms: Vec<String> = groups.iter().map(|group| group.rooms().join(" + ")).collect();
+        info!("Zone groups: {}", rooms.join(", "));
+        self.groups = groups;
+        // A zone that joins a group stops playing its own track, and starts
+        // afresh when it leaves again.
+        let members: Vec<String> = self
+            .devices
+            .keys()
+            .filter(|uuid| !self.is_coordinator(uuid))
+            .cloned()
+            .collect();
+        for uuid in members {
+            self.forget(&uuid);
+        }
+    }
+
+    fn transport_changed(&mut self, device: &Speaker, transport_state: TransportState) {
+        if !self.is_coordinator(&device.uuid) {
+            return;
+        }
+        let state = self.device(device);
+        if state.transport_state != Some(transport_state) {
+            trace!("{} is {:?}", state.room, transport_state);
+            state.transport_state = Some(transport_state);
+        }
+        // Only time spent playing counts towards a play.
+        if let Some(pending) = &mut state.pending {
+            match (pending.playing_since, transport_state == TransportState::Playing) {
+                (Some(since), false) => {
+                    pending.listened += since.elapsed();
+                    pending.playing_since = None;
+                }
+                (None, true) => pending.playing_since = Some(Instant::now()),
+                _ => {}
+            }
+        }
+        self.check_plays();
+    }
+
+    fn track_changed(&mut self, device: &Speaker, track: Track) {
+        if !self.is_coordinator(&device.uuid) {
+            return;
+        }
+        let rooms = self.group(&device.uuid).map(ZoneGroup::rooms);
+        // Check if the track has changed in this zone
+        let state = self.device(device);
+        if state.track_uri.as_deref() == Some(track.uri.as_str()) {
+            return;
+        }
+        let now = chrono::Utc::now();
+        state.track_uri = Some(track.uri.clone());
+        state.first_seen = Some(now);
+        info!("{} by {} is playing on {}", track.title, track.artist, state.room);
+        // A zone that has not told us its transport state yet is taken to be playing.
+        let playing = state.transport_state.is_none_or(|transport| transport == TransportState::Playing);
+        let record = PlayRecord {
+            at: now.timestamp(),
+            room: Some(state.room.clone()),
+            rooms,
+        };
+        let previous = state.pending.replace(PendingPlay {
+            track,
+            record,
+            listened: Duration::ZERO,
+            playing_since: playing.then(Instant::now),
+        });
+        if let Some(previous) = previous {
+            self.record_skip(previous);
+        }
+        self.check_plays();
+    }
+
+    /// Records the tracks that have been listened to long enough.
+    fn check_plays(&mut self) {
+        let rules = &self.rules;
+        let qualified: Vec<PendingPlay> = self
+            .devices
+            .values_mut()
+            .filter_map(|state| {
+                let pending = state.pending.as_ref()?;
+                if rules.qualifies(pending.track.duration, pending.listened()) {
+                    state.pending.take()
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for play in qualified {
+            self.record_play(play);
+        }
+    }
+
+    fn record_play(&mut self, play: PendingPlay) {
+        let PendingPlay { track, record, .. } = play;
+        let room = record.room.clone();
+        self.changed = true;
+
+        let ser_track = self.tracks.entry(track.uri.clone()).or_insert_with(|| SerTrack {
+            track: clone_track(&track),
+            play_history: None,
+            skip_history: None,
+        });
+        let first_play = !ser_track.has_plays();
+        ser_track.play_history.get_or_insert_with(Vec::new).push(record);
+
+        // Add this track to the youtube playlist if config option is enabled
+        if first_play && self.config.create_sonotube_play_list() {
+            info!("sonotube: Adding {} by {} to playlist", track.title, track.artist);
+            self.sender.send(Play { track, room }).unwrap();
+        }
+    }
+
+    fn record_skip(&mut self, play: PendingPlay) {
+        let PendingPlay { track, record, .. } = play;
+        info!(
+            "{} by {} was skipped on {}",
+            track.title,
+            track.artist,
+            record.room.as_deref().unwrap_or_default()
+        );
+        self.changed = true;
+        let ser_track = self.tracks.entry(track.uri.clone()).or_insert_with(|| SerTrack {
+            track,
+            play_history: None,
+            skip_history: None,
+        });
+        ser_track.skip_history.get_or_insert_with(Vec::new).push(record);
+    }
+
+    /// Drops the state of a zone that went away. What it was playing did not count.
+    fn forget(&mut self, uuid: &str) {
+        if let Some(pending) = self.devices.remove(uuid).and_then(|state| state.pending) {
+            self.record_skip(pending);
+        }
+    }
+
+    fn save_if_changed(&mut self) {
+        if self.changed {
+            SonoTube::save_tracks(TRACK_CACHE, &self.tracks);
+            self.changed = false;
+        }
+    }
+}
+
+/// Event subscriptions to the speakers that accepted one.
+struct SpeakerEvents {
+    listener: EventListener,
+    subscriber: Subscriber,
+    subscriptions: Vec<(Subscription, Speaker)>,
+    /// The household's topology, which any one speaker reports, and that speaker's UUID.
+    topology: Option<(Subscription, String)>,
+}
+
+impl SpeakerEvents {
+    /// Listens for events on the address `device` can reach us at.
+    fn start(device: &Speaker, port: u16) -> Option<SpeakerEvents> {
+        let local_ip = match upnp::local_address_for(device.ip) {
+            Ok(ip) => ip,
+            Err(e) => {
+                warn!("No route to the speakers for events, polling instead: {}", e);
+                return None;
+            }
+        };
+        let listener = match EventListener::start(local_ip, port) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Unable to listen for speaker events, polling instead: {}", e);
+                return None;
+            }
+        };
+        let subscriber = Subscriber::new(&listener.callback_url);
+        Some(SpeakerEvents {
+            listener,
+            subscriber,
+            subscriptions: Vec::new(),
+            topology: None,
+        })
+    }
+
+    /// Subscribes to a speaker's track changes. Returns false if it has to be polled instead.
+    async fn add(&mut self, device: &Speaker) -> bool {
+        match self.subscriber.subscribe(&upnp::service_url(device.ip, AVTRANSPORT_EVENT_PATH)).await {
+            Ok(subscription) => {
+                self.subscriptions.push((subscription, device.clone()));
+                true
+            }
+            Err(e) => {
+                warn!("Polling {}, which did not accept a subscription: {}", device.name, e);
+                false
+            }
+        }
+    }
+
+    /// Forgets a speaker that went away. There is no one left to unsubscribe from.
+    fn remove(&mut self, uuid: &str) {
+        self.subscriptions.retain(|(_, device)| device.uuid != uuid);
+        if self.topology.as_ref().is_some_and(|(_, topology_uuid)| topology_uuid == uuid) {
+            self.topology = None;
+        }
+    }
+
+    /// Subscribes to the zone groups through the first of `devices` that
+    /// accepts, unless already subscribed.
+    async fn follow_topology(&mut self, devices: &[Speaker]) {
+        if self.topology.is_some() {
+            return;
+        }
+        for device in devices {
+            match self.subscriber.subscribe(&upnp::service_url(device.ip, TOPOLOGY_EVENT_PATH)).await {
+                Ok(subscription) => {
+                    self.topology = Some((subscription, device.uuid.clone()));
+                    return;
+                }
+                Err(e) => warn!("{} did not accept a zone group subscription: {}", device.name, e),
+            }
+        }
+        if !devices.is_empty() {
+            warn!("Polling the zone groups");
+        }
+    }
+
+    fn speaker(&self, sid: &str) -> Option<&Speaker> {
+        self.subscriptions
+            .iter()
+            .find(|(subscription, _)| subscription.sid == sid)
+            .map(|(_, device)| device)
+    }
+
+    /// Renews subscriptions that are due, subscribing again to speakers that
+    /// forgot theirs. Returns the speakers that have to be polled from now on.
+    async fn renew(&mut self) -> Vec<Speaker> {
+        let mut polled = Vec::new();
+        let mut subscriptions = Vec::new();
+        for (mut subscription, device) in std::mem::take(&mut self.subscriptions) {
+            if !subscription.is_due() {
+                subscriptions.push((subscription, device));
+                continue;
+            }
+            if let Err(e) = self.subscriber.renew(&mut subscription).await {
+                warn!("Unable to renew the subscription to {}: {}", device.name, e);
+                match self.subscriber.subscribe(&subscription.event_url).await {
+                    Ok(renewed) => subscription = renewed,
+                    Err(e) => {
+                        warn!("Polling {}, which refused a new subscription: {}", device.name, e);
+                        polled.push(device);
+                        continue;
+                    }
+                }
+            }
+            subscriptions.push((subscription, device));
+        }
+        self.subscriptions = subscriptions;
+
+        if self.topology.as_ref().is_some_and(|(topology, _)| topology.is_due()) {
+            let (mut topology, uuid) = self.topology.take().unwrap();
+            self.topology = match self.subscriber.renew(&mut topology).await {
+                Ok(()) => Some((topology, uuid)),
+                Err(e) => {
+                    warn!("Unable to renew the zone group subscription: {}", e);
+                    let renewed = self.subscriber.subscribe(&topology.event_url).await.ok();
+                    renewed.map(|topology| (topology, uuid))
+                }
+            };
+        }
+        polled
+    }
+
+    async fn stop(self) {
+        for (subscription, device) in &self.subscriptions {
+            if let Err(e) = self.subscriber.unsubscribe(subscription).await {
+                warn!("Unable to unsubscribe from {}: {}", device.name, e);
+            }
+        }
+        if let Some((topology, _)) = &self.topology {
+            if let Err(e) = self.subscriber.unsubscribe(topology).await {
+                warn!("Unable to unsubscribe from the zone groups: {}", e);
+            }
+        }
+        self.listener.stop().await;
+    }
+}
+
 impl SonoTube {
     pub async fn start_sonos_track_monitor(
-        sender: mpsc::Sender<Track>,
+        sender: mpsc::UnboundedSender<Play>,
         flag: Arc<AtomicBool>,
         config: Config,
     ) -> JoinHandle<()> {
         info!("Starting track monitor...");
         tokio::spawn(async move {
-            // find sonos devices on the network
-            let devices = sonos::discover().await.unwrap();
-            info!("Found {} sonos devices on your network", devices.len());
-
-            let mut tracks = SonoTube::load_tracks(TRACK_CACHE);
+            let mut log = TrackLog::new(SonoTube::load_tracks(TRACK_CACHE), sender, config.clone());
 
             if config.send_previous_tracks() {
-                for ser_track in tracks.values() {
+                for ser_track in log.tracks.values().filter(|ser_track| ser_track.has_plays()) {
                     let track = ser_track.clone().track;
-                    sender.send(track).unwrap();
+                    log.sender.send(Play { track, room: None }).unwrap();
                 }
             }
 
-            // poll found devices for new tracks
-            let mut last_track_uri = String::from("");
+            // Speakers report track changes as they happen; the ones that
+            // cannot are polled. Both change as speakers come and go.
+            let static_ips = config.sonos_speaker_ips().unwrap_or_default();
+            let mut registry = DeviceRegistry::default();
+            let mut discovery = DiscoverySchedule::new(config.sonos_discovery_interval());
+            let mut events: Option<SpeakerEvents> = None;
+            let mut polled: Vec<Speaker> = Vec::new();
+            let poll_interval = config.sonos_poll_interval();
+            let mut next_poll = Instant::now();
             while flag.load(std::sync::atomic::Ordering::Relaxed) {
-                for device in &devices {
-                    if let Ok(track) = device.track().await {
-                        // Check if the track has changed
-                        if &track.uri == &last_track_uri {
-                            continue;
+                if discovery.is_due() {
+                    match discovery::discover(&static_ips).await {
+                        Ok(found) => {
+                            discovery.succeeded();
+                            let changes = registry.update(found);
+                            SonoTube::devices_changed(changes, &registry, &mut events, &mut polled, &mut log, &config)
+                                .await;
+                        }
+                        Err(e) => {
+                            let delay = discovery.failed();
+                            warn!("Unable to discover speakers, trying again in {:?}: {}", delay, e);
                         }
-                        let title = track.title.clone();
-                        let artist = track.artist.clone();
-                        last_track_uri = track.uri.clone();
-
-                        // See if we played this track before
-                        if tracks.contains_key(&track.uri) {
-                            let ser_track = tracks.get_mut(&track.uri).unwrap();
-                            match ser_track.play_history {
-                                Some(ref mut history) => {
-                                    history.push(chrono::Utc::now().timestamp());
+                    }
+                }
+
+                match &mut events {
+                    Some(events) => {
+                        // Wait briefly, so the flag and renewals are checked often.
+                        match events.listener.next(EVENT_WAIT).await.map(|n| (events.speaker(&n.sid), n.event)) {
+                            Some((_, SpeakerEvent::Topology(groups))) => log.groups_changed(groups),
+                            Some((Some(device), SpeakerEvent::Transport(event))) => {
+                                if let Some(state) = event.transport_state {
+                                    log.transport_changed(device, state);
                                 }
-                                None => {
-                                    let mut history = Vec::new();
-                                    history.push(chrono::Utc::now().timestamp());
-                                    ser_track.play_history = Some(history);
+                                if let Some(track) = event.track {
+                                    log.track_changed(device, track);
                                 }
                             }
-                        } else {
-                            let now = chrono::Utc::now();
-                            let ser_track = SerTrack {
-                                track: track,
-                                play_history: Some(vec![now.timestamp()]),
-                            };
-
-                            // Add this track to the youtube playlist if config option is enabled
-                            if config.create_sonotube_play_list() {
-                                info!("sonotube: Adding {} by {} to playlist", title, artist);
-                                sender.send(ser_track.clone().track).unwrap();
-                            }
+                            _ => {}
+                        }
+                        polled.extend(events.renew().await);
+                    }
+                    None => task::sleep(EVENT_WAIT).await,
+                }
 
-                            tracks.insert(ser_track.track.uri.clone(), ser_track);
+                if Instant::now() >= next_poll {
+                    if events.as_ref().is_none_or(|events| events.topology.is_none()) {
+                        SonoTube::poll_zone_groups(&registry.devices(), &mut log).await;
+                    }
+                    let coordinators: Vec<Speaker> =
+                        polled.iter().filter(|device| log.is_coordinator(&device.uuid)).cloned().collect();
+                    for device in &coordinators {
+                        if let Ok(state) = device.transport_state().await {
+                            log.transport_changed(device, state);
+                        }
+                        if let Ok(track) = device.track().await {
+                            log.track_changed(device, track);
                         }
-                        info!("{} by {} is playing on {}", title, artist, device.name);
                     }
+                    next_poll = Instant::now() + poll_interval;
                 }
 
-                SonoTube::save_tracks(TRACK_CACHE, &tracks);
-                task::sleep(Duration::from_secs(30)).await
+                log.check_plays();
+                log.save_if_changed();
+            }
+            if let Some(events) = events {
+                events.stop().await;
             }
             info!("Track monitor exiting...")
         })
     }
 
+    /// Stops following speakers that went away and starts following new ones.
+    async fn devices_changed(
+        changes: DeviceChanges,
+        registry: &DeviceRegistry,
+        events: &mut Option<SpeakerEvents>,
+        polled: &mut Vec<Speaker>,
+        log: &mut TrackLog,
+        config: &Config,
+    ) {
+        if changes.appeared.is_empty() && changes.disappeared.is_empty() {
+            return;
+        }
+        for device in &changes.disappeared {
+            polled.retain(|polled| polled.uuid != device.uuid);
+            if let Some(events) = events.as_mut() {
+                events.remove(&device.uuid);
+            }
+            // A speaker with a new address still plays the same track.
+            if !changes.appeared.iter().any(|appeared| appeared.uuid == device.uuid) {
+                log.forget(&device.uuid);
+            }
+        }
+
+        if events.is_none() && config.sonos_events() {
+            if let Some(device) = changes.appeared.first() {
+                *events = SpeakerEvents::start(device, config.sonos_event_port());
+            }
+        }
+        // Members of a group get subscribed too, in case they leave it.
+        for device in changes.appeared {
+            let subscribed = match events.as_mut() {
+                Some(events) => events.add(&device).await,
+                None => false,
+            };
+            if !subscribed {
+                polled.push(device);
+            }
+        }
+        if let Some(events) = events.as_mut() {
+            events.follow_topology(&registry.devices()).await;
+        }
+        info!("Following {} sonos devices on your network", registry.devices().len());
+    }
+
+    async fn poll_zone_groups(devices: &[Speaker], log: &mut TrackLog) {
+        let device = match devices.first() {
+            Some(device) => device,
+            None => return,
+        };
+        match upnp::zone_groups(&upnp::service_url(device.ip, TOPOLOGY_CONTROL_PATH)).await {
+            Ok(groups) => log.groups_changed(groups),
+            Err(e) => warn!("Unable to get the zone groups from {}: {}", device.name, e),
+        }
+    }
+
     fn load_tracks(file_name: &str) -> HashMap<String, SerTrack> {
         use std::fs;
         let tracks_path = SonoTube::get_tracks_path(file_name);
@@ -184,7 +695,12 @@ async fn test_load_save_tracks() {
         track.uri.clone(),
         SerTrack {
             track: track,
-            play_history: Some(vec![0]),
+            play_history: Some(vec![PlayRecord {
+                at: 0,
+                room: None,
+                rooms: None,
+            }]),
+            skip_history: None,
         },
     );
 
@@ -205,3 +721,179 @@ async fn test_load_save_tracks() {
     assert_eq!(Duration::from_secs(10), test_track.track.duration);
     assert_eq!("test_artist", &test_track.track.artist);
 }
+
+#[cfg(test)]
+fn test_track(uri: &str, title: &str) -> Track {
+    Track {
+        title: title.to_string(),
+        artist: "test_artist".to_string(),
+        album: None,
+        queue_position: 1,
+        uri: uri.to_string(),
+        duration: Duration::from_secs(180),
+        running_time: Duration::ZERO,
+    }
+}
+
+#[test]
+fn test_track_log_keeps_state_per_zone() {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut log = TrackLog::new(HashMap::new(), sender, Config::for_fake_tube("http://127.0.0.1"));
+    log.rules = PlayRules::default();
+    let kitchen = discovery::test_speaker("Kitchen", "RINCON_KITCHEN01400", [127, 0, 0, 1]);
+    let office = discovery::test_speaker("Office", "RINCON_OFFICE01400", [127, 0, 0, 1]);
+
+    // Polling both rooms again and again counts each track once.
+    for _ in 0..3 {
+        log.track_changed(&kitchen, test_track("uri:a", "A"));
+        log.transport_changed(&kitchen, TransportState::Playing);
+        log.track_changed(&office, test_track("uri:b", "B"));
+    }
+    let rooms = |log: &TrackLog, uri: &str| -> Vec<Option<String>> {
+        log.tracks[uri].play_history.iter().flatten().map(|play| play.room.clone()).collect()
+    };
+    assert_eq!(rooms(&log, "uri:a"), vec![Some(String::from("Kitchen"))]);
+    assert_eq!(rooms(&log, "uri:b"), vec![Some(String::from("Office"))]);
+    let kitchen_state = &log.devices["RINCON_KITCHEN01400"];
+    assert_eq!(kitchen_state.transport_state, Some(TransportState::Playing));
+    assert!(kitchen_state.first_seen.is_some());
+
+    // A known track playing in another room is another play, but not sent on again.
+    log.track_changed(&kitchen, test_track("uri:b", "B"));
+    assert_eq!(rooms(&log, "uri:b"), vec![Some(String::from("Office")), Some(String::from("Kitchen"))]);
+    let sent: Vec<(String, Option<String>)> = std::iter::from_fn(|| receiver.try_recv().ok())
+        .map(|play| (play.track.uri, play.room))
+        .collect();
+    assert_eq!(
+        sent,
+        vec![
+            (String::from("uri:a"), Some(String::from("Kitchen"))),
+            (String::from("uri:b"), Some(String::from("Office"))),
+        ]
+    );
+}
+
+#[test]
+fn test_load_play_history_without_rooms() {
+    let ser_track: SerTrack = serde_json::from_value(serde_json::json!({
+        "track": {
+            "title": "t", "artist": "a", "album": null, "queue_position": 1, "uri": "u",
+            "duration": { "secs": 10, "nanos": 0 }, "running_time": { "secs": 0, "nanos": 0 },
+        },
+        "play_history": [5, { "at": 6, "room": "Kitchen" }],
+    }))
+    .unwrap();
+    assert_eq!(
+        ser_track.play_history.unwrap(),
+        vec![
+            PlayRecord {
+                at: 5,
+                room: None,
+                rooms: None,
+            },
+            PlayRecord {
+                at: 6,
+                room: Some(String::from("Kitchen")),
+                rooms: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_track_log_counts_group_plays_once() {
+    let (sender, _receiver) = mpsc::unbounded_channel();
+    let mut log = TrackLog::new(HashMap::new(), sender, Config::default());
+    log.rules = PlayRules::default();
+    let kitchen = discovery::test_speaker("Kitchen", "RINCON_KITCHEN01400", [127, 0, 0, 1]);
+    let office = discovery::test_speaker("Office", "RINCON_OFFICE01400", [127, 0, 0, 1]);
+    let member = |speaker: &Speaker| upnp::ZoneMember {
+        uuid: speaker.uuid.clone(),
+        room: speaker.name.clone(),
+    };
+    log.groups_changed(vec![ZoneGroup {
+        coordinator: office.uuid.clone(),
+        members: vec![member(&kitchen), member(&office)],
+    }]);
+
+    // Both rooms report the coordinator's track.
+    for device in [&kitchen, &office, &kitchen] {
+        log.track_changed(device, test_track("uri:a", "A"));
+    }
+    let plays = log.tracks["uri:a"].play_history.clone().unwrap();
+    assert_eq!(plays.len(), 1);
+    assert_eq!(plays[0].room.as_deref(), Some("Office"));
+    assert_eq!(plays[0].rooms, Some(vec![String::from("Office"), String::from("Kitchen")]));
+
+    // After the kitchen leaves the group, each room plays for itself.
+    log.groups_changed(vec![
+        ZoneGroup {
+            coordinator: office.uuid.clone(),
+            members: vec![member(&office)],
+        },
+        ZoneGroup {
+            coordinator: kitchen.uuid.clone(),
+            members: vec![member(&kitchen)],
+        },
+    ]);
+    log.track_changed(&office, test_track("uri:a", "A"));
+    log.track_changed(&kitchen, test_track("uri:a", "A"));
+    let rooms: Vec<Option<Vec<String>>> = log.tracks["uri:a"]
+        .play_history
+        .iter()
+        .flatten()
+        .map(|play| play.rooms.clone())
+        .collect();
+    assert_eq!(
+        rooms,
+        vec![
+            Some(vec![String::from("Office"), String::from("Kitchen")]),
+            Some(vec![String::from("Kitchen")]),
+        ]
+    );
+}
+
+#[test]
+fn test_track_log_records_skips() {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut log = TrackLog::new(HashMap::new(), sender, Config::for_fake_tube("http://127.0.0.1"));
+    let kitchen = discovery::test_speaker("Kitchen", "RINCON_KITCHEN01400", [127, 0, 0, 1]);
+    let listened = |log: &mut TrackLog, secs: u64| {
+        let pending = log.devices.get_mut(&kitchen.uuid).unwrap().pending.as_mut().unwrap();
+        pending.listened = Duration::from_secs(secs);
+    };
+
+    // A is skipped after a few seconds.
+    log.transport_changed(&kitchen, TransportState::Playing);
+    log.track_changed(&kitchen, test_track("uri:a", "A"));
+    log.track_changed(&kitchen, test_track("uri:b", "B"));
+    assert!(!log.tracks["uri:a"].has_plays());
+    assert_eq!(log.tracks["uri:a"].skip_history.as_ref().unwrap().len(), 1);
+
+    // B counts once half of it was listened to, while it keeps playing.
+    listened(&mut log, 60);
+    log.check_plays();
+    assert!(!log.tracks.contains_key("uri:b"));
+    listened(&mut log, 90);
+    log.check_plays();
+    assert_eq!(log.tracks["uri:b"].play_history.as_ref().unwrap().len(), 1);
+    log.track_changed(&kitchen, test_track("uri:b", "B"));
+    assert_eq!(log.tracks["uri:b"].play_history.as_ref().unwrap().len(), 1);
+
+    // Time spent paused does not count.
+    log.track_changed(&kitchen, test_track("uri:a", "A"));
+    log.transport_changed(&kitchen, TransportState::PausedPlayback);
+    let pending = log.devices[&kitchen.uuid].pending.as_ref().unwrap();
+    assert!(pending.playing_since.is_none());
+    listened(&mut log, 89);
+    log.check_plays();
+    assert!(!log.tracks["uri:a"].has_plays());
+    log.transport_changed(&kitchen, TransportState::Playing);
+    listened(&mut log, 90);
+    log.check_plays();
+    assert!(log.tracks["uri:a"].has_plays());
+
+    // Only plays are sent on, each track once.
+    let sent: Vec<String> = std::iter::from_fn(|| receiver.try_recv().ok()).map(|play| play.track.uri).collect();
+    assert_eq!(sent, vec![String::from("uri:b"), String::from("uri:a")]);
+}
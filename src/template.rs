@@ -0,0 +1,123 @@
+//! Fills in playlist title and description templates such as
+//! `{creator} - {date:%Y-%m-%d}`.
+//!
+//! Variables are `{creator}`, `{date}` (with an optional chrono format after a
+//! colon), `{room}`, `{track_count}` and `{first_artist}`. Unknown variables
+//! are left as they are and `{{` / `}}` stand for literal braces.
+
+use crate::models::TubeTrack;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+
+pub const DEFAULT_TITLE_TEMPLATE: &str = "{creator} - {date}";
+pub const DEFAULT_DESCRIPTION_TEMPLATE: &str = "playlist created by {creator} on {date}";
+const DEFAULT_DATE_FORMAT: &str = "%a %b %e %Y %T";
+
+/// The values a playlist template can refer to.
+#[derive(Debug, Clone)]
+pub struct PlaylistVars {
+    pub creator: String,
+    pub date: DateTime<Local>,
+    pub room: Option<String>,
+    /// How many tracks the playlist is created with.
+    pub track_count: usize,
+    pub first_artist: Option<String>,
+}
+
+impl PlaylistVars {
+    pub fn new(creator: &str) -> Self {
+        PlaylistVars {
+            creator: creator.to_string(),
+            date: Local::now(),
+            room: None,
+            track_count: 0,
+            first_artist: None,
+        }
+    }
+
+    pub fn with_room(mut self, room: Option<String>) -> Self {
+        self.room = room;
+        self
+    }
+
+    pub fn with_tracks(mut self, tracks: &[TubeTrack]) -> Self {
+        self.track_count = tracks.len();
+        self.first_artist = tracks.first().map(|track| track.artist.clone());
+        self
+    }
+
+    fn value(&self, name: &str, format: Option<&str>) -> Option<String> {
+        match name {
+            "creator" => Some(self.creator.clone()),
+            "date" => {
+                let items = StrftimeItems::new(format.unwrap_or(DEFAULT_DATE_FORMAT));
+                // A bad format would make chrono fail while formatting.
+                if items.clone().any(|item| item == Item::Error) {
+                    return None;
+                }
+                Some(self.date.format_with_items(items).to_string())
+            }
+            "room" => Some(self.room.clone().unwrap_or_default()),
+            "track_count" => Some(self.track_count.to_string()),
+            "first_artist" => Some(self.first_artist.clone().unwrap_or_default()),
+            _ => None,
+        }
+    }
+}
+
+pub fn render(template: &str, vars: &PlaylistVars) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            rendered.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        let end = match tail.find('}') {
+            Some(end) if tail.starts_with('{') => end,
+            _ => {
+                // A lone brace is kept as it is.
+                rendered.push_str(&tail[..1]);
+                rest = &tail[1..];
+                continue;
+            }
+        };
+        let variable = &tail[1..end];
+        let (name, format) = match variable.split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (variable, None),
+        };
+        match vars.value(name.trim(), format) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&tail[..=end]),
+        }
+        rest = &tail[end + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[test]
+fn test_render() {
+    use chrono::TimeZone;
+    let vars = PlaylistVars {
+        creator: String::from("sonotube"),
+        date: Local.with_ymd_and_hms(2024, 3, 10, 18, 30, 0).unwrap(),
+        room: Some(String::from("Kitchen")),
+        track_count: 12,
+        first_artist: Some(String::from("Dua Lipa")),
+    };
+
+    assert_eq!(render("{creator} - {date:%Y-%m-%d}", &vars), "sonotube - 2024-03-10");
+    assert_eq!(
+        render("{track_count} tracks in the {room}, starting with {first_artist}", &vars),
+        "12 tracks in the Kitchen, starting with Dua Lipa"
+    );
+    assert_eq!(render(DEFAULT_TITLE_TEMPLATE, &vars), "sonotube - Sun Mar 10 2024 18:30:00");
+    assert_eq!(render("{{literal}} {unknown} {creator", &vars), "{literal} {unknown} {creator");
+    assert_eq!(render("{date:%Q}", &vars), "{date:%Q}");
+    assert_eq!(render("{room}", &PlaylistVars::new("toptastic")), "");
+}
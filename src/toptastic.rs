@@ -1,71 +1,233 @@
-use crate::{config::Config, models::TubeTrack, tube::Tube};
+use crate::match_cache::SharedMatchCache;
+use crate::models::{ErrorReason, PlaylistPrivacy, SearchFilters};
+use crate::overrides::{Override, Overrides, SharedOverrides};
+use crate::template::{self, PlaylistVars};
+use crate::quota::{Priority, QuotaUsage, SharedQuotaLedger};
+use crate::tube::{PlaylistTarget, TubeError};
+use crate::config::{Config, DEFAULT_ACCOUNT};
+use crate::{models::TubeTrack, tube::Tube};
 use actix_web::web::Data;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{delete, get, post, put, web, App, HttpResponse, HttpServer, Responder};
 use async_std::sync::Mutex;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Playlist {
-    title: String,
-    description: String,
+    /// Title and description templates; the configured ones when left out.
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    /// The configured privacy when left out.
+    #[serde(default)]
+    privacy: Option<PlaylistPrivacy>,
     tracks: Vec<TubeTrack>,
+    /// Make the playlist hold just this chart, in chart order, removing
+    /// videos left over from earlier charts.
+    #[serde(default)]
+    sync: bool,
+    /// The account the playlist goes to; the default one when left out.
+    #[serde(default)]
+    account: Option<String>,
+    /// Overrides the configured search filters for this playlist's tracks.
+    #[serde(default)]
+    search_filters: Option<SearchFilters>,
+}
+
+/// A requested track and the video it got, if any.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessedTrack {
+    #[serde(flatten)]
+    track: TubeTrack,
+    /// Not added for lack of quota; send it again once the quota resets.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deferred: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MatchKey {
+    artist: String,
+    title: String,
+}
+
+/// A track's Sonos URI, or its artist and title.
+#[derive(Serialize, Deserialize)]
+pub struct OverrideKey {
+    #[serde(default)]
+    uri: Option<String>,
+    #[serde(default)]
+    artist: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OverrideRequest {
+    #[serde(flatten)]
+    key: OverrideKey,
+    /// A video id, or "skip".
+    video: Override,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorBody {
+    error: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct TopTastic {
-    tube: Tube,
+    /// One tube per account, by account name.
+    tubes: HashMap<String, Tube>,
+    /// Tubes for playlists other than the account's own, by account and title.
+    playlists: HashMap<(String, String), Tube>,
+    overrides: SharedOverrides,
     config: Config,
 }
 
 impl TopTastic {
-    pub async fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
-        let tube = Tube::new();
+    pub async fn new(
+        config: &Config,
+        match_cache: SharedMatchCache,
+        quota: SharedQuotaLedger,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut tubes = HashMap::new();
+        for account in config.account_names() {
+            let account_config = config.for_account(&account).unwrap();
+            // Someone is waiting on the HTTP response, so toptastic may use the quota reserve.
+            let tube = Tube::new(&account_config, match_cache.clone(), quota.clone()).with_priority(Priority::Urgent);
+            tubes.insert(account, tube);
+        }
         Ok(Self {
-            tube,
+            tubes,
+            playlists: HashMap::new(),
+            overrides: Overrides::in_memory().shared(),
             config: config.clone(),
         })
     }
 
-    pub async fn create_playlist(
-        &mut self,
-        title: String,
-        description: String,
-        tracks: Vec<TubeTrack>,
-    ) -> Vec<TubeTrack> {
+    /// Sets the track overrides every account's tube honors and the
+    /// `/overrides` endpoints edit.
+    pub fn with_overrides(mut self, overrides: SharedOverrides) -> Self {
+        self.tubes = self
+            .tubes
+            .into_iter()
+            .map(|(account, tube)| (account, tube.with_overrides(overrides.clone())))
+            .collect();
+        self.overrides = overrides;
+        self
+    }
+
+    pub async fn create_playlist(&mut self, playlist: Playlist) -> Result<Vec<ProcessedTrack>, TubeError> {
         // Check if the create_toptastic_playlist flag is set to true
         let mut processed_tracks = Vec::new();
+        let Playlist {
+            title,
+            description,
+            privacy,
+            tracks,
+            sync,
+            account,
+            search_filters,
+        } = playlist;
 
         if self.config.create_toptastic_play_list() {
-            info!("Creating playlist {} with {} tracks", title, tracks.len());
+            let account = account.unwrap_or_else(|| String::from(DEFAULT_ACCOUNT));
+            let vars = PlaylistVars::new("toptastic").with_tracks(&tracks);
+            let title = template::render(&title.unwrap_or_else(|| self.config.playlist_title_template()), &vars);
+            let description = template::render(
+                &description.unwrap_or_else(|| self.config.playlist_description_template()),
+                &vars,
+            );
+            let privacy = privacy.unwrap_or_else(|| self.config.playlist_privacy());
+            let search_filters = self.config.search_filters().with_overrides(&search_filters.unwrap_or_default());
+            let tube = match self.playlist_tube(&account, &title) {
+                Some(tube) => tube,
+                None => return Err(TubeError::Auth(format!("there is no {} account", account))),
+            };
+            tube.set_privacy(privacy);
+            tube.set_search_filters(search_filters);
+
+            info!("Creating playlist {} with {} tracks for {}", title, tracks.len(), account);
 
             for track in tracks {
-                let video_id = self.tube.process_track(&track, &title, &description).await;
+                let video_id = match tube.process_track(&track, &title, &description).await {
+                    Ok(video_id) => video_id,
+                    // Problems with a single track leave it out; anything else
+                    // would fail the remaining tracks too.
+                    Err(e) if is_track_error(&e) => {
+                        warn!("Skipping {} by {}. {}", track.title, track.artist, e);
+                        None
+                    }
+                    Err(e) => return Err(e),
+                };
+                // The caller is told instead, so nothing is left for the tube to retry.
+                let deferred = !tube.take_deferred().is_empty();
                 let processed_track = TubeTrack {
                     id: track.id,
                     title: track.title,
                     artist: track.artist,
                     video_id,
+                    duration_secs: track.duration_secs,
                 };
-                processed_tracks.push(processed_track);
+                processed_tracks.push(ProcessedTrack {
+                    track: processed_track,
+                    deferred,
+                });
+            }
+
+            if sync {
+                let mut video_ids: Vec<String> = Vec::new();
+                for processed in &processed_tracks {
+                    if let Some(video_id) = tube.playlist_video(&processed.track) {
+                        if !video_ids.contains(video_id) {
+                            video_ids.push(video_id.clone());
+                        }
+                    }
+                }
+                tube.arrange_playlist(&video_ids).await?;
             }
         }
         else {
             info!("create_toptastic_playlist flag is set to false. Skipping playlist creation");
         }
-        processed_tracks
+        Ok(processed_tracks)
+    }
+
+    /// The tube that fills `account`'s playlist called `title`. The account's
+    /// own playlist takes the first title it is asked for; any other title
+    /// gets our playlist of that name, found or created once and reused.
+    fn playlist_tube(&mut self, account: &str, title: &str) -> Option<&mut Tube> {
+        let account_tube = self.tubes.get_mut(account)?;
+        if account_tube.playlist_title().is_none_or(|own| own == title) {
+            return Some(account_tube);
+        }
+        let key = (account.to_string(), title.to_string());
+        let tube = self.playlists.entry(key).or_insert_with(|| {
+            account_tube
+                .for_new_playlist()
+                .with_playlist(PlaylistTarget::Title(title.to_string()))
+        });
+        Some(tube)
     }
 
     pub async fn start_server(self) -> std::io::Result<()> {
         let port = 3030;
         info!("Starting server on port {}", port);
-        let toptastic = TopTastic::new(&self.config).await.unwrap();
+        let toptastic = self;
         HttpServer::new(move || {
             App::new()
                 .app_data(Data::new(Arc::new(Mutex::new(toptastic.clone()))))
                 .service(create_playlist)
+                .service(invalidate_match)
                 .service(status)
+                .service(list_overrides)
+                .service(set_override)
+                .service(remove_override)
                 .service(log_message)
         })
         .bind(("127.0.0.1", port))?
@@ -74,10 +236,41 @@ impl TopTastic {
     }
 }
 
+fn is_track_error(error: &TubeError) -> bool {
+    matches!(error, TubeError::NoResults { .. }) || error.reason() == Some(&ErrorReason::VideoNotFound)
+}
+
+fn error_response(error: &TubeError) -> HttpResponse {
+    let mut response = match error {
+        TubeError::Api {
+            reason: ErrorReason::QuotaExceeded,
+            ..
+        } => HttpResponse::TooManyRequests(),
+        TubeError::Auth(_) => HttpResponse::ServiceUnavailable(),
+        TubeError::NoResults { .. } => HttpResponse::NotFound(),
+        TubeError::Transport(_) | TubeError::Api { .. } | TubeError::Parse(_) => HttpResponse::BadGateway(),
+    };
+    response.json(ErrorBody {
+        error: error.to_string(),
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Status {
+    status: String,
+    quota: QuotaUsage,
+}
+
 #[get("/status")]
-async fn status() -> impl Responder {
+async fn status(data: web::Data<Arc<Mutex<TopTastic>>>) -> impl Responder {
     info!("Status request received");
-    HttpResponse::Ok().body("Server is running")
+    // All tubes share the quota ledger and the match cache.
+    let quota = data.lock().await.tubes[DEFAULT_ACCOUNT].quota();
+    let usage = quota.lock().unwrap().usage();
+    HttpResponse::Ok().json(Status {
+        status: String::from("Server is running"),
+        quota: usage,
+    })
 }
 
 #[post("/log")]
@@ -92,25 +285,128 @@ async fn create_playlist(
     playlist: web::Json<Playlist>,
 ) -> impl Responder {
     info!("Create playlist request received");
-    let title = playlist.title.clone();
-    let description = playlist.description.clone();
-    let tracks = playlist.tracks.clone();
-
     let mut toptastic = data.lock().await;
-    let process_tracks = toptastic.create_playlist(title, description, tracks).await;
-    HttpResponse::Created().json(process_tracks)
+    if let Some(account) = &playlist.account {
+        if !toptastic.tubes.contains_key(account) {
+            return HttpResponse::BadRequest().json(ErrorBody {
+                error: format!("there is no {} account", account),
+            });
+        }
+    }
+    match toptastic.create_playlist(playlist.into_inner()).await {
+        // Accepted rather than created while some tracks wait for the quota.
+        Ok(process_tracks) if process_tracks.iter().any(|track| track.deferred) => {
+            HttpResponse::Accepted().json(process_tracks)
+        }
+        Ok(process_tracks) => HttpResponse::Created().json(process_tracks),
+        Err(e) => error_response(&e),
+    }
+}
+
+#[delete("/matches")]
+async fn invalidate_match(
+    data: web::Data<Arc<Mutex<TopTastic>>>,
+    key: web::Json<MatchKey>,
+) -> impl Responder {
+    info!("Invalidate match request received for {} by {}", key.title, key.artist);
+    let match_cache = data.lock().await.tubes[DEFAULT_ACCOUNT].match_cache();
+    let removed = match_cache.lock().unwrap().invalidate(&key.artist, &key.title);
+    if removed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+fn missing_override_key() -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorBody {
+        error: String::from("an override needs a uri, or an artist and a title"),
+    })
+}
+
+fn override_save_failed(error: std::io::Error) -> HttpResponse {
+    warn!("Unable to save the overrides: {}", error);
+    HttpResponse::InternalServerError().json(ErrorBody {
+        error: format!("unable to save the overrides: {}", error),
+    })
+}
+
+#[get("/overrides")]
+async fn list_overrides(data: web::Data<Arc<Mutex<TopTastic>>>) -> impl Responder {
+    let overrides = data.lock().await.overrides.clone();
+    let entries = overrides.lock().unwrap().entries().clone();
+    HttpResponse::Ok().json(entries)
+}
+
+#[put("/overrides")]
+async fn set_override(
+    data: web::Data<Arc<Mutex<TopTastic>>>,
+    request: web::Json<OverrideRequest>,
+) -> impl Responder {
+    let OverrideRequest { key, video } = request.into_inner();
+    info!("Override request received: {:?}", video);
+    let overrides = data.lock().await.overrides.clone();
+    let mut overrides = overrides.lock().unwrap();
+    let saved = match key {
+        OverrideKey { uri: Some(uri), .. } => overrides.set_uri(&uri, video),
+        OverrideKey {
+            artist: Some(artist),
+            title: Some(title),
+            ..
+        } => overrides.set_track(&artist, &title, video),
+        _ => return missing_override_key(),
+    };
+    match saved {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => override_save_failed(e),
+    }
+}
+
+#[delete("/overrides")]
+async fn remove_override(
+    data: web::Data<Arc<Mutex<TopTastic>>>,
+    key: web::Json<OverrideKey>,
+) -> impl Responder {
+    let overrides = data.lock().await.overrides.clone();
+    let mut overrides = overrides.lock().unwrap();
+    let removed = match key.into_inner() {
+        OverrideKey { uri: Some(uri), .. } => overrides.remove_uri(&uri),
+        OverrideKey {
+            artist: Some(artist),
+            title: Some(title),
+            ..
+        } => overrides.remove_track(&artist, &title),
+        _ => return missing_override_key(),
+    };
+    match removed {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => override_save_failed(e),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fake_tube::FakeTube;
+    use crate::match_cache::MatchCache;
+    use crate::models::test_track;
+    use crate::quota::QuotaLedger;
     use actix_web::http::StatusCode;
     use actix_web::{test, App};
 
+    fn test_toptastic_parts(config: &Config) -> (SharedMatchCache, SharedQuotaLedger) {
+        let match_cache = MatchCache::in_memory(config.match_cache_ttl()).shared();
+        let quota = QuotaLedger::in_memory(config.quota_budget(), config.quota_reserve()).shared();
+        (match_cache, quota)
+    }
+
     #[actix_rt::test]
     async fn test_create_playlist() {
-        let config = Config::new();
-        let toptastic = TopTastic::new(&config).await.unwrap();
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
         let mut app = test::init_service(
             App::new()
                 .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
@@ -121,33 +417,249 @@ mod tests {
         let req = test::TestRequest::post()
             .uri("/playlists")
             .set_json(&Playlist {
-                title: "Test Playlist".into(),
-                description: "Test Description".into(),
+                title: Some("Test Playlist".into()),
+                description: Some("Test Description".into()),
+                privacy: None,
                 tracks: vec![
-                    TubeTrack {
-                        id: "test1".into(),
-                        title: "we are never getting back together".into(),
-                        artist: "Taylor Swift".into(),
-                        video_id: None,
-                    },
-                    TubeTrack {
-                        id: "test2".into(),
-                        title: "Houdini".into(),
-                        artist: "Dua Lipa".into(),
-                        video_id: None,
-                    },
+                    test_track("test1", "we are never getting back together", "Taylor Swift"),
+                    test_track("test2", "Houdini", "Dua Lipa"),
                 ],
+                sync: false,
+                account: None,
+                search_filters: None,
             })
             .to_request();
 
         let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let tracks: Vec<TubeTrack> = test::read_body_json(resp).await;
+        let video_ids: Vec<Option<String>> = tracks.into_iter().map(|track| track.video_id).collect();
+        assert_eq!(
+            video_ids,
+            vec![Some("WA4iX5D9Z64".to_string()), Some("suAR1PYFNYA".to_string())]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_create_playlist_reports_errors() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist),
+        )
+        .await;
+
+        let playlist = Playlist {
+            title: Some("Test Playlist".into()),
+            description: Some("Test Description".into()),
+            privacy: None,
+            tracks: vec![test_track("test1", "Houdini", "Dua Lipa")],
+            sync: false,
+            account: None,
+            search_filters: None,
+        };
+        fake.fail_next("search", 403, "quotaExceeded", 1);
+        let req = test::TestRequest::post().uri("/playlists").set_json(&playlist).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        assert!(body.error.contains("QuotaExceeded"));
+    }
+
+    #[actix_rt::test]
+    async fn test_create_playlist_reports_deferred_tracks() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota.clone()).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist),
+        )
+        .await;
+
+        quota.lock().unwrap().exhaust();
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(serde_json::json!({
+                "tracks": [{ "id": "test1", "title": "Houdini", "artist": "Dua Lipa", "videoId": null }],
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body[0]["id"], "test1");
+        assert_eq!(body[0]["videoId"], serde_json::Value::Null);
+        assert_eq!(body[0]["deferred"], true);
+        assert_eq!(fake.call_count("search"), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_create_playlist_for_account() {
+        let fake = FakeTube::start().await;
+        let mut config = serde_json::to_value(fake.config()).unwrap();
+        config["accounts"] = serde_json::json!({ "alice": { "accessToken": "alice-token" } });
+        let config: Config = serde_json::from_value(config).unwrap();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist),
+        )
+        .await;
+
+        let playlist = |account: &str| Playlist {
+            title: None,
+            description: None,
+            privacy: None,
+            tracks: vec![test_track("test1", "Houdini", "Dua Lipa")],
+            sync: false,
+            account: Some(account.into()),
+            search_filters: None,
+        };
+        let req = test::TestRequest::post().uri("/playlists").set_json(playlist("alice")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let playlist_id = fake.playlist_ids().pop().unwrap();
+        assert_eq!(fake.playlist_owner(&playlist_id).as_deref(), Some("alice-token"));
+        assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["suAR1PYFNYA"]);
+
+        let req = test::TestRequest::post().uri("/playlists").set_json(playlist("carol")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorBody = test::read_body_json(resp).await;
+        assert!(body.error.contains("carol"));
+    }
+
+    #[actix_rt::test]
+    async fn test_create_playlist_sync_keeps_chart_order() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist),
+        )
+        .await;
+
+        let chart = |tracks: Vec<TubeTrack>| Playlist {
+            title: Some("Top Chart".into()),
+            description: Some("Test Description".into()),
+            privacy: None,
+            tracks,
+            sync: true,
+            account: None,
+            search_filters: None,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(chart(vec![
+                test_track("1", "Shape of You", "Ed Sheeran"),
+                test_track("2", "Houdini", "Dua Lipa"),
+            ]))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let playlist_ids = fake.playlist_ids();
+        assert_eq!(playlist_ids.len(), 1);
+        let playlist_id = playlist_ids[0].clone();
+        assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["JGwWNGJdvx8", "suAR1PYFNYA"]);
+
+        // Next week Taylor Swift goes in at the top, Houdini drops to second
+        // and Ed Sheeran drops out.
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(chart(vec![
+                test_track("3", "We Are Never Getting Back Together", "Taylor Swift"),
+                test_track("4", "Houdini", "Dua Lipa"),
+            ]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        assert_eq!(fake.playlist_ids(), vec![playlist_id.clone()]);
+        assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["WA4iX5D9Z64", "suAR1PYFNYA"]);
+        assert_eq!(fake.call_count("playlistItems.delete"), 1);
+        assert_eq!(fake.call_count("playlistItems.update"), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_create_playlist_with_overrides() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(serde_json::json!({
+                "title": "{creator}: {track_count} tracks from {first_artist}",
+                "privacy": "public",
+                "searchFilters": { "regionCode": "US", "videoCategoryId": "10" },
+                "tracks": [
+                    { "id": "test1", "title": "Houdini", "artist": "Dua Lipa", "videoId": null },
+                    { "id": "test2", "title": "Shape of You", "artist": "Ed Sheeran", "videoId": null },
+                ],
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let playlist_id = fake.playlist_ids().pop().unwrap();
+        assert_eq!(
+            fake.playlist_title(&playlist_id).as_deref(),
+            Some("toptastic: 2 tracks from Dua Lipa")
+        );
+        assert_eq!(fake.playlist_privacy(&playlist_id).as_deref(), Some("public"));
+        assert_eq!(fake.last_search_param("regionCode").as_deref(), Some("US"));
+        assert_eq!(fake.last_search_param("videoCategoryId").as_deref(), Some("10"));
+
+        // A later request with other overrides gets a playlist of its own.
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(serde_json::json!({
+                "title": "{first_artist} and more",
+                "privacy": "unlisted",
+                "tracks": [
+                    { "id": "test2", "title": "Shape of You", "artist": "Ed Sheeran", "videoId": null },
+                ],
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let second_id = fake.playlist_ids().pop().unwrap();
+        assert_ne!(second_id, playlist_id);
+        assert_eq!(fake.playlist_title(&second_id).as_deref(), Some("Ed Sheeran and more"));
+        assert_eq!(fake.playlist_privacy(&second_id).as_deref(), Some("unlisted"));
+        assert_eq!(fake.playlist_video_ids(&second_id), vec!["JGwWNGJdvx8"]);
+        assert_eq!(
+            fake.playlist_title(&playlist_id).as_deref(),
+            Some("toptastic: 2 tracks from Dua Lipa")
+        );
     }
 
     #[actix_rt::test]
     async fn test_log_message() {
         let config = Config::new();
-        let toptastic = TopTastic::new(&config).await.unwrap();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
         let mut app = test::init_service(
             App::new()
                 .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
@@ -163,4 +675,175 @@ mod tests {
         let resp = test::call_service(&mut app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
     }
+
+    #[actix_rt::test]
+    async fn test_invalidate_match() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache.clone(), quota).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist)
+                .service(invalidate_match),
+        )
+        .await;
+
+        let track = test_track("test1", "Houdini", "Dua Lipa");
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(&Playlist {
+                title: Some("Test Playlist".into()),
+                description: Some("Test Description".into()),
+                privacy: None,
+                tracks: vec![track.clone()],
+                sync: false,
+                account: None,
+                search_filters: None,
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+        assert!(match_cache.lock().unwrap().get(&track).is_some());
+
+        let key = MatchKey {
+            artist: "dua lipa".into(),
+            title: "houdini".into(),
+        };
+        let req = test::TestRequest::delete().uri("/matches").set_json(&key).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(match_cache.lock().unwrap().get(&track).is_none());
+
+        let req = test::TestRequest::delete().uri("/matches").set_json(&key).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_status_reports_quota() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist)
+                .service(status),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(&Playlist {
+                title: Some("Test Playlist".into()),
+                description: Some("Test Description".into()),
+                privacy: None,
+                tracks: vec![test_track("test1", "Houdini", "Dua Lipa")],
+                sync: false,
+                account: None,
+                search_filters: None,
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/status").to_request();
+        let body: Status = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.quota.used, 200);
+        assert_eq!(body.quota.calls["search.list"], 1);
+        assert_eq!(body.quota.remaining, config.quota_budget() - 200);
+    }
+
+    #[actix_rt::test]
+    async fn test_overrides() {
+        let fake = FakeTube::start().await;
+        let config = fake.config();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        let toptastic = TopTastic::new(&config, match_cache, quota)
+            .await
+            .unwrap()
+            .with_overrides(Overrides::in_memory().shared());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(create_playlist)
+                .service(list_overrides)
+                .service(set_override)
+                .service(remove_override),
+        )
+        .await;
+
+        let set = |body: serde_json::Value| test::TestRequest::put().uri("/overrides").set_json(body).to_request();
+        let resp = test::call_service(&app, set(serde_json::json!({ "uri": "test1", "video": "kAr40kE0Shp" }))).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(
+            &app,
+            set(serde_json::json!({ "artist": "Dua Lipa", "title": "Houdini", "video": "skip" })),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, set(serde_json::json!({ "title": "Houdini", "video": "skip" }))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get().uri("/overrides").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["uris"]["test1"], "kAr40kE0Shp");
+        assert_eq!(body["tracks"]["dua lipa|houdini"], "skip");
+
+        let req = test::TestRequest::post()
+            .uri("/playlists")
+            .set_json(serde_json::json!({
+                "tracks": [
+                    { "id": "test1", "title": "Shape of You", "artist": "Ed Sheeran", "videoId": null },
+                    { "id": "test2", "title": "Houdini", "artist": "Dua Lipa", "videoId": null },
+                ],
+            }))
+            .to_request();
+        let tracks: Vec<TubeTrack> = test::call_and_read_body_json(&app, req).await;
+        let video_ids: Vec<Option<String>> = tracks.into_iter().map(|track| track.video_id).collect();
+        assert_eq!(video_ids, vec![Some("kAr40kE0Shp".to_string()), None]);
+        assert_eq!(fake.call_count("search"), 0);
+
+        let remove = || {
+            test::TestRequest::delete()
+                .uri("/overrides")
+                .set_json(serde_json::json!({ "uri": "test1" }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, remove()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = test::call_service(&app, remove()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_override_save_failure() {
+        let config = Config::default();
+        let (match_cache, quota) = test_toptastic_parts(&config);
+        // A directory where the file should be cannot be written.
+        let dir = std::env::temp_dir().join(format!("toptastic_overrides_dir_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let overrides = Overrides::load_path(dir.clone()).shared();
+        let toptastic = TopTastic::new(&config, match_cache, quota)
+            .await
+            .unwrap()
+            .with_overrides(overrides.clone());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(Arc::new(Mutex::new(toptastic))))
+                .service(set_override),
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/overrides")
+            .set_json(serde_json::json!({ "uri": "test1", "video": "skip" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // The lock is not poisoned, so tubes can still look overrides up.
+        assert!(overrides.lock().is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
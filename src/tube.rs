@@ -1,122 +1,647 @@
+use crate::api::{ApiClient, ApiError};
+use crate::auth::{OAuthSettings, TokenSource};
+use crate::config::Config;
+use crate::deferred::DeferredTracks;
+use crate::match_cache::{MatchCache, SharedMatchCache};
+use crate::matcher::{self, VideoMatch};
 use crate::models::*;
-use dirs;
+use crate::normalize::NormalizedTrack;
+use crate::overrides::{Override, Overrides, SharedOverrides};
+use crate::playlist_parts::PlaylistParts;
+use crate::template::{self, PlaylistVars};
+use crate::video_filter::VideoFilter;
+use crate::quota::{ApiCall, Priority, SharedQuotaLedger};
 use log::{error, trace, info, warn};
-use reqwest::Client;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::path::{Path, PathBuf};
-use yup_oauth2::{AccessToken, InstalledFlowAuthenticator, InstalledFlowReturnMethod};
-
-const CLIENT_SECRETS_PATH: &str = r"D:\secrets\sonotube\client_secrets.json";
-const TOKEN_CACHE_FILE: &str = "sonotube_token_cache.json";
-const PLAYLISTS_URI: &str = "https://www.googleapis.com/youtube/v3/playlists";
-const SEARCH_URI: &str = "https://www.googleapis.com/youtube/v3/search";
-const PLAYLIST_ITEMS_URI: &str = "https://www.googleapis.com/youtube/v3/playlistItems";
+use std::time::Duration;
+
+const PLAYLISTS_PATH: &str = "playlists";
+const PLAYLISTS_PAGE_SIZE: &str = "50";
+const PLAYLIST_ITEMS_PAGE_SIZE: &str = "50";
+const SEARCH_PATH: &str = "search";
+const PLAYLIST_ITEMS_PATH: &str = "playlistItems";
+const VIDEOS_PATH: &str = "videos";
 pub const API_KEY_VAR: &str = "SONOTUBE_API_KEY";
 
+/// Which playlist a tube adds its tracks to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaylistTarget {
+    /// Create a new playlist with the title passed to `process_track`.
+    New,
+    /// Always use the playlist with this id.
+    Id(String),
+    /// Use our playlist with this title, creating it if there is none.
+    Title(String),
+}
+
+#[derive(Debug)]
+pub enum TubeError {
+    /// We could not get credentials, or Google rejected the ones we sent.
+    Auth(String),
+    /// The request never got a response, e.g. a connection failure or timeout.
+    Transport(reqwest::Error),
+    /// YouTube answered with an error status.
+    Api {
+        status: u16,
+        reason: ErrorReason,
+        message: String,
+    },
+    /// A response body was not what we expected.
+    Parse(reqwest::Error),
+    /// Search found nothing we are confident is the track.
+    NoResults { artist: String, title: String },
+}
+
+impl TubeError {
+    pub fn reason(&self) -> Option<&ErrorReason> {
+        match self {
+            TubeError::Api { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl From<ApiError> for TubeError {
+    fn from(error: ApiError) -> Self {
+        match error {
+            ApiError::Transport(e) => TubeError::Transport(e),
+            ApiError::Google { status: 401, message, .. } => TubeError::Auth(message),
+            ApiError::Google { status, reason, message } => TubeError::Api { status, reason, message },
+            ApiError::Parse(e) => TubeError::Parse(e),
+        }
+    }
+}
+
+impl std::fmt::Display for TubeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TubeError::Auth(message) => write!(f, "authentication failed: {}", message),
+            TubeError::Transport(e) => write!(f, "request failed: {}", e),
+            TubeError::Api { status, reason, message } => write!(f, "YouTube error {} {:?}: {}", status, reason, message),
+            TubeError::Parse(e) => write!(f, "unexpected response: {}", e),
+            TubeError::NoResults { artist, title } => write!(f, "no video found for {} by {}", title, artist),
+        }
+    }
+}
+
+impl std::error::Error for TubeError {}
+
 #[derive(Debug, Clone)]
 pub struct Tube {
     pub seen: HashSet<String>,
-    token: Option<AccessToken>,
-    client: Client,
+    token_source: Option<TokenSource>,
+    static_token: Option<String>,
+    oauth: OAuthSettings,
+    api_key: Option<String>,
+    min_match_confidence: f64,
+    search_max_results: u64,
+    search_filters: SearchFilters,
+    search_variants: usize,
+    video_filter: VideoFilter,
+    duration_tolerance: Duration,
+    match_cache: SharedMatchCache,
+    overrides: SharedOverrides,
+    priority: Priority,
+    deferred: DeferredTracks,
+    api: ApiClient,
+    playlist_target: PlaylistTarget,
+    playlist_parts: PlaylistParts,
+    /// The first part of the playlist, which the parts index is keyed by.
+    first_playlist_id: Option<String>,
+    /// The part tracks are currently added to.
     playlist_id: Option<String>,
+    /// Title and description of the first part; later parts are named after it.
+    playlist_title: String,
+    playlist_description: String,
+    playlist_item_count: u32,
+    playlist_item_limit: u32,
+    privacy: PlaylistPrivacy,
+    /// Videos already in any part of the playlist.
+    playlist_videos: HashSet<String>,
+    /// Match cache keys of tracks already in the playlist, mapped to their video.
+    playlist_tracks: HashMap<String, String>,
 }
 
 impl Tube {
-    pub fn new() -> Tube {
+    pub fn new(config: &Config, match_cache: SharedMatchCache, quota: SharedQuotaLedger) -> Tube {
         Tube {
             seen: HashSet::new(),
-            token: None,
-            client: Client::new(),
+            token_source: None,
+            static_token: config.access_token(),
+            oauth: config.oauth_settings(),
+            api_key: config.api_key().or_else(|| env::var(API_KEY_VAR).ok()),
+            min_match_confidence: config.min_match_confidence(),
+            search_max_results: config.search_max_results(),
+            search_filters: config.search_filters(),
+            search_variants: config.search_variants(),
+            video_filter: config.video_filter().unwrap_or_else(|e| {
+                error!("Ignoring the video filter: {}", e);
+                VideoFilter::default()
+            }),
+            duration_tolerance: config.duration_tolerance(),
+            match_cache,
+            overrides: Overrides::in_memory().shared(),
+            priority: Priority::Deferrable,
+            deferred: DeferredTracks::in_memory(),
+            api: ApiClient::new(config, quota),
+            playlist_target: PlaylistTarget::New,
+            playlist_parts: PlaylistParts::in_memory(),
+            first_playlist_id: None,
             playlist_id: None,
+            playlist_title: String::new(),
+            playlist_description: String::new(),
+            playlist_item_count: 0,
+            playlist_item_limit: config.playlist_item_limit(),
+            privacy: config.playlist_privacy(),
+            playlist_videos: HashSet::new(),
+            playlist_tracks: HashMap::new(),
         }
     }
 
-    fn get_token_cache_path(&mut self, file_name: &str) -> PathBuf {
-        let mut token_cache = dirs::cache_dir().expect("The cache directory was not found.");
-        token_cache.push(file_name);
-        token_cache
+    /// Sets how this tube's work competes for the remaining daily quota.
+    pub fn with_priority(mut self, priority: Priority) -> Tube {
+        self.priority = priority;
+        self
     }
 
-    pub fn generate_sonotube_title_and_description(creator: &str) -> (String, String) {
-        let now = chrono::Local::now().format("%a %b %e %Y %T").to_string();
-        let title = format!("{creator} - {now}");
-        let description = format!("playlist created by {creator} on {now}");
-        (title, description)
+    /// Sets which playlist this tube adds its tracks to.
+    pub fn with_playlist(mut self, target: PlaylistTarget) -> Tube {
+        self.playlist_target = target;
+        self
     }
 
-    async fn authenticate(&mut self) {
-        
-        // Load the client secrets from the client_secrets.json path.
-        let secrets_path = Path::new(CLIENT_SECRETS_PATH);
-        let secret = yup_oauth2::read_application_secret(secrets_path)
-            .await
-            .expect(secrets_path.to_str().unwrap());
-
-        // Create an authenticator that uses an InstalledFlow to authenticate. The
-        // authentication tokens are persisted to a file named tokencache.json. The
-        // authenticator takes care of caching tokens to disk and refreshing tokens once
-        // they've expired.
-        let auth =
-            InstalledFlowAuthenticator::builder(secret, InstalledFlowReturnMethod::HTTPRedirect)
-                .persist_tokens_to_disk(
-                    self.get_token_cache_path(TOKEN_CACHE_FILE)
-                        .to_str()
-                        .unwrap(),
-                )
-                .build()
-                .await
-                .unwrap();
-
-        // Obtain a token that can be sent e.g. as Bearer token.
-        let scopes = &["https://www.googleapis.com/auth/youtube"];
-
-        self.token = match auth.token(scopes).await {
-            Ok(token) => Some(token),
-            Err(err) => {
-                error!("Failed to obtain access token: {:?}", err);
-                panic!("{:?}", err);
-            }
+    /// A copy that fills a new playlist of its own, for tracks that should
+    /// not go to this tube's playlist.
+    pub fn for_new_playlist(&self) -> Tube {
+        Tube {
+            seen: HashSet::new(),
+            deferred: DeferredTracks::in_memory(),
+            playlist_target: PlaylistTarget::New,
+            playlist_parts: PlaylistParts::in_memory(),
+            first_playlist_id: None,
+            playlist_id: None,
+            playlist_title: String::new(),
+            playlist_description: String::new(),
+            playlist_item_count: 0,
+            playlist_videos: HashSet::new(),
+            playlist_tracks: HashMap::new(),
+            ..self.clone()
         }
     }
 
-    pub async fn process_track(&mut self, track: &TubeTrack, title: &str, description: &str)-> Option<String> {
-        
-        if self.playlist_id.is_none() {
-            //let now = chrono::Local::now().format("%a %b %e %Y %T").to_string();
-            //let title = format!("sonotube - {}", now);
-            //let description = format!("playlist created by sonotube on {}", now);
-            self.playlist_id = self.insert_playlist(&title, &description).await;
+    /// Sets where tracks wait for the quota to reset.
+    pub fn with_deferred(mut self, deferred: DeferredTracks) -> Tube {
+        self.deferred = deferred;
+        self
+    }
+
+    /// Sets the index that remembers the parts of playlists that grew too big.
+    pub fn with_playlist_parts(mut self, playlist_parts: PlaylistParts) -> Tube {
+        self.playlist_parts = playlist_parts;
+        self
+    }
+
+    /// Sets the tracks pinned to a video, or to none, instead of searched for.
+    pub fn with_overrides(mut self, overrides: SharedOverrides) -> Tube {
+        self.overrides = overrides;
+        self
+    }
+
+    pub fn match_cache(&self) -> SharedMatchCache {
+        self.match_cache.clone()
+    }
+
+    pub fn quota(&self) -> SharedQuotaLedger {
+        self.api.quota()
+    }
+
+    /// The most quota processing this track could cost.
+    fn quota_needed(&self, track: &TubeTrack) -> u64 {
+        if self.playlist_tracks.contains_key(&MatchCache::key(&track.artist, &track.title)) {
+            return 0;
+        }
+        let mut cost = ApiCall::PlaylistItemsInsert.cost();
+        if self.playlist_id.is_none() || self.playlist_item_count >= self.playlist_item_limit {
+            cost += ApiCall::PlaylistsInsert.cost();
+        }
+        let cached = self.match_cache.lock().unwrap().get(track).is_some();
+        if track.video_id.is_none() && !cached {
+            cost += ApiCall::Search.cost() + ApiCall::VideosList.cost();
         }
+        cost
+    }
 
-        trace!("Tube:: Received {} by {}", track.title, track.artist);
-        if self.seen.insert(track.id.clone()) {
-            info!("Tube::processing track {} by {}", track.title, track.artist);
+    /// The token to send, refreshed by the authenticator once it has expired.
+    async fn bearer_token(&self) -> Result<String, TubeError> {
+        match (&self.static_token, &self.token_source) {
+            (Some(token), _) => Ok(token.clone()),
+            (None, Some(token_source)) => token_source.token().await,
+            (None, None) => Err(TubeError::Auth(String::from("not authenticated"))),
+        }
+    }
 
-            // if we already have a video id for this track, just add it to the playlist
-            if let Some(video_id) = &track.video_id {
-                self.add_video_to_playlist(&self.playlist_id.clone().unwrap(), video_id).await;
-                return Some(video_id.clone());
+    fn is_authenticated(&self) -> bool {
+        self.static_token.is_some() || self.token_source.is_some()
+    }
+
+    /// Sends an authorized request. If Google rejects the token before it
+    /// expires, e.g. after a revocation, it is refreshed and the call retried once.
+    async fn execute_authorized<F>(&self, call: ApiCall, build: F) -> Result<reqwest::Response, TubeError>
+    where
+        F: Fn(&str) -> reqwest::RequestBuilder,
+    {
+        let token = self.bearer_token().await?;
+        match self.api.execute(call, || build(&token)).await {
+            Err(ApiError::Google { status: 401, .. }) if self.token_source.is_some() => {
+                warn!("{} was not authorized, refreshing the access token", call.name());
+                let token = self.token_source.as_ref().unwrap().refreshed_token().await?;
+                Ok(self.api.execute(call, || build(&token)).await?)
             }
-            
-            // otherwise, find the video id and add it to the playlist
-            match self.find_video_id_for_track(track).await {
-                Some(video_id) => {
-                   
-                    self.add_video_to_playlist(&self.playlist_id.clone().unwrap(), &video_id).await;
-                    Some(video_id)
-                },
-                None => {
-                    warn!("Tube:: No video found for {} by {}", track.title, track.artist);
-                    None
-                }
+            result => Ok(result?),
+        }
+    }
+
+    /// Like `execute_authorized`, parsing the response body as JSON.
+    async fn json_authorized<T, F>(&self, call: ApiCall, build: F) -> Result<T, TubeError>
+    where
+        T: serde::de::DeserializeOwned,
+        F: Fn(&str) -> reqwest::RequestBuilder,
+    {
+        let response = self.execute_authorized(call, build).await?;
+        Ok(response.json::<T>().await.map_err(ApiError::Parse)?)
+    }
+
+    /// Renders the configured playlist title and description templates.
+    pub fn generate_title_and_description(config: &Config, vars: &PlaylistVars) -> (String, String) {
+        let title = template::render(&config.playlist_title_template(), vars);
+        let description = template::render(&config.playlist_description_template(), vars);
+        (title, description)
+    }
+
+    /// Sets who can see the playlists this tube creates from now on.
+    pub fn set_privacy(&mut self, privacy: PlaylistPrivacy) {
+        self.privacy = privacy;
+    }
+
+    /// Sets the filters searches for new tracks use from now on.
+    pub fn set_search_filters(&mut self, filters: SearchFilters) {
+        self.search_filters = filters;
+    }
+
+    /// Loads the token `sonotube auth login` cached. Never prompts, so a
+    /// missing or revoked authorization fails instead of blocking.
+    async fn authenticate(&mut self) -> Result<(), TubeError> {
+        self.token_source = Some(TokenSource::new(&self.oauth).await?);
+        Ok(())
+    }
+
+    /// Adds the track to the playlist, creating the playlist first if needed,
+    /// and returns the video id. Returns `Ok(None)` for tracks that were
+    /// already processed, are already in the playlist, are overridden to be
+    /// skipped or are deferred for lack of quota.
+    pub async fn process_track(
+        &mut self,
+        track: &TubeTrack,
+        title: &str,
+        description: &str,
+    ) -> Result<Option<String>, TubeError> {
+
+        // An override pins the video like a pre-set video id does.
+        let pinned;
+        let track = match self.overrides.lock().unwrap().get(track) {
+            Some(Override::Skip) => {
+                info!("Tube::skipping {} by {} - overridden", track.title, track.artist);
+                return Ok(None);
             }
-        } else {
+            Some(Override::Video(video_id)) => {
+                pinned = TubeTrack {
+                    video_id: Some(video_id),
+                    ..track.clone()
+                };
+                &pinned
+            }
+            None => track,
+        };
+
+        if !self.seen.contains(&track.id) {
+            let cost = self.quota_needed(track);
+            if !self.quota().lock().unwrap().allows(cost, self.priority) {
+                warn!(
+                    "Tube::deferring {} by {} - not enough quota left today",
+                    track.title, track.artist
+                );
+                self.deferred.push(track);
+                return Ok(None);
+            }
+        }
+
+        let playlist_id = self.resolve_playlist(title, description).await?;
+
+        trace!("Tube:: Received {} by {}", track.title, track.artist);
+        if !self.seen.insert(track.id.clone()) {
             info!(
                 "Tube::ingoring track {} by {} - already processed",
                 track.title, track.artist
             );
-            None
+            return Ok(None);
+        }
+
+        info!("Tube::processing track {} by {}", track.title, track.artist);
+        let result = self.add_track(track, &playlist_id).await;
+        if let Err(e) = &result {
+            error!("Tube:: Failed to add {} by {}. {}", track.title, track.artist, e);
+            // A track without a usable match stays seen so it does not cost
+            // another search. Anything else may work the next time it comes along.
+            if !matches!(e, TubeError::NoResults { .. }) {
+                self.seen.remove(&track.id);
+            }
+        }
+        result
+    }
+
+    /// Adds the track's video unless the playlist already has it. Returns the
+    /// video id when it was added.
+    async fn add_track(&mut self, track: &TubeTrack, playlist_id: &str) -> Result<Option<String>, TubeError> {
+        let key = MatchCache::key(&track.artist, &track.title);
+        if let Some(video_id) = self.playlist_tracks.get(&key) {
+            info!(
+                "Tube::ignoring track {} by {} - already in the playlist as {}",
+                track.title, track.artist, video_id
+            );
+            return Ok(None);
+        }
+
+        let cached = self.match_cache.lock().unwrap().get(track).cloned();
+        let video_id = if let Some(video_id) = &track.video_id {
+            // we already have a video id for this track
+            video_id.clone()
+        } else if let Some(cached) = cached {
+            // or we matched this track on an earlier run
+            info!(
+                "Tube::using cached match {} for {} by {}",
+                cached.video_id, track.title, track.artist
+            );
+            cached.video_id
+        } else {
+            // otherwise, search for it
+            let video_match = self.find_video_id_for_track(track).await?;
+            self.match_cache.lock().unwrap().insert(track, &video_match);
+            video_match.video_id
+        };
+
+        if self.playlist_videos.contains(&video_id) {
+            info!(
+                "Tube::ignoring track {} by {} - {} is already in the playlist",
+                track.title, track.artist, video_id
+            );
+            self.playlist_tracks.insert(key, video_id);
+            return Ok(None);
+        }
+
+        let mut playlist_id = playlist_id.to_string();
+        if self.playlist_item_count >= self.playlist_item_limit {
+            playlist_id = self.roll_over().await?;
+        }
+        let mut result = self.add_video_to_playlist(&playlist_id, &video_id).await;
+        if let Err(e) = &result {
+            if e.reason() == Some(&ErrorReason::PlaylistFull) {
+                // Our count was off, e.g. someone added videos by hand.
+                playlist_id = self.roll_over().await?;
+                result = self.add_video_to_playlist(&playlist_id, &video_id).await;
+            }
+        }
+        if let Err(e) = &result {
+            if e.reason() == Some(&ErrorReason::VideoNotFound) {
+                // Don't keep handing out a match for a video that is gone.
+                self.match_cache.lock().unwrap().invalidate(&track.artist, &track.title);
+            }
+        }
+        result?;
+        self.playlist_videos.insert(video_id.clone());
+        self.playlist_tracks.insert(key, video_id.clone());
+        Ok(Some(video_id))
+    }
+
+    /// Retries tracks that were deferred for lack of quota. Tracks that still
+    /// do not fit in today's budget are deferred again.
+    pub async fn process_deferred(&mut self, title: &str, description: &str) {
+        if self.deferred.is_empty() {
+            return;
+        }
+        let deferred = self.deferred.take();
+        info!("Tube::retrying {} deferred tracks", deferred.len());
+        for track in deferred {
+            if let Err(e) = self.process_track(&track, title, description).await {
+                error!("Tube:: Deferred track {} by {} failed. {}", track.title, track.artist, e);
+            }
+        }
+    }
+
+    /// Takes the tracks that were deferred, for callers that report them
+    /// instead of retrying them.
+    pub fn take_deferred(&mut self) -> Vec<TubeTrack> {
+        self.deferred.take()
+    }
+
+    /// The id of the playlist to add tracks to, looking it up or creating it
+    /// the first time.
+    async fn resolve_playlist(&mut self, title: &str, description: &str) -> Result<String, TubeError> {
+        if let Some(playlist_id) = &self.playlist_id {
+            return Ok(playlist_id.clone());
+        }
+
+        let existing = match self.playlist_target.clone() {
+            PlaylistTarget::New => None,
+            PlaylistTarget::Id(playlist_id) => Some(playlist_id),
+            PlaylistTarget::Title(title) => {
+                let found = self.find_playlist_by_title(&title).await?;
+                if let Some(playlist_id) = &found {
+                    info!("Tube::reusing playlist {} ({})", title, playlist_id);
+                }
+                found
+            }
+        };
+        self.playlist_title = match &self.playlist_target {
+            PlaylistTarget::Title(title) => title.clone(),
+            _ => title.to_string(),
+        };
+        self.playlist_description = description.to_string();
+
+        let first = match existing {
+            Some(first) => first,
+            None => {
+                let title = self.playlist_title.clone();
+                let playlist_id = self.insert_playlist(&title, description).await?;
+                self.first_playlist_id = Some(playlist_id.clone());
+                self.playlist_id = Some(playlist_id.clone());
+                self.playlist_item_count = 0;
+                return Ok(playlist_id);
+            }
+        };
+
+        let parts = self.playlist_parts.parts(&first);
+        for part in &parts {
+            self.seed_from_playlist(part).await?;
+        }
+        let current = parts.last().cloned().unwrap_or_else(|| first.clone());
+        let details = self.playlist_details(&current).await?;
+        self.playlist_item_count = details
+            .as_ref()
+            .and_then(|playlist| playlist.content_details.as_ref()?.item_count)
+            .unwrap_or(0);
+        if let PlaylistTarget::Id(_) = self.playlist_target {
+            // Later parts are named after the first one.
+            let first_details = if current == first {
+                details
+            } else {
+                self.playlist_details(&first).await?
+            };
+            if let Some(title) = first_details.and_then(|playlist| playlist.snippet?.title) {
+                self.playlist_title = title;
+            }
+        }
+        info!(
+            "Tube::adding to playlist {} ({} of {} items)",
+            current, self.playlist_item_count, self.playlist_item_limit
+        );
+
+        self.first_playlist_id = Some(first);
+        self.playlist_id = Some(current.clone());
+        Ok(current)
+    }
+
+    /// Continues the playlist in a new part once the current one is full.
+    async fn roll_over(&mut self) -> Result<String, TubeError> {
+        // Both ids are set together when the playlist is resolved.
+        let first = self
+            .first_playlist_id
+            .clone()
+            .or_else(|| self.playlist_id.clone())
+            .unwrap_or_default();
+        let part = self.playlist_parts.parts(&first).len() + 1;
+        let title = format!("{} (part {})", self.playlist_title, part);
+        warn!(
+            "Tube::playlist {:?} is full, continuing in {}",
+            self.playlist_id, title
+        );
+
+        let description = self.playlist_description.clone();
+        let playlist_id = self.insert_playlist(&title, &description).await?;
+        self.playlist_parts.add_part(&first, &playlist_id);
+        self.playlist_id = Some(playlist_id.clone());
+        self.playlist_item_count = 0;
+        Ok(playlist_id)
+    }
+
+    /// Whether the playlist is one of the parts this tube adds to.
+    fn is_own_playlist(&self, playlist_id: &str) -> bool {
+        match &self.first_playlist_id {
+            Some(first) => self.playlist_parts.parts(first).iter().any(|part| part == playlist_id),
+            None => false,
+        }
+    }
+
+    /// Remembers what an existing playlist already holds, so tracks added on
+    /// earlier runs are not added again.
+    async fn seed_from_playlist(&mut self, playlist_id: &str) -> Result<(), TubeError> {
+        let items = self.list_playlist_items(playlist_id).await?;
+        self.playlist_videos
+            .extend(items.iter().map(|item| item.video_id().to_string()));
+        self.playlist_tracks = self.match_cache.lock().unwrap().keys_for_videos(&self.playlist_videos);
+        info!(
+            "Tube::playlist {} already has {} videos, {} of them known tracks",
+            playlist_id,
+            self.playlist_videos.len(),
+            self.playlist_tracks.len()
+        );
+        Ok(())
+    }
+
+    /// Pages through all items of a playlist, in playlist order.
+    pub async fn list_playlist_items(&mut self, playlist_id: &str) -> Result<Vec<PlaylistItemResponse>, TubeError> {
+        if !self.is_authenticated() {
+            self.authenticate().await?;
+        }
+
+        let url = self.api.endpoint(PLAYLIST_ITEMS_PATH);
+        let mut items = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("part", "snippet"),
+                ("playlistId", playlist_id),
+                ("maxResults", PLAYLIST_ITEMS_PAGE_SIZE),
+            ];
+            if let Some(token) = &page_token {
+                query.push(("pageToken", token.as_str()));
+            }
+            let page: PlaylistItemListResponse = self
+                .json_authorized(ApiCall::PlaylistItemsList, |token| {
+                    self.api.client().get(&url).query(&query).bearer_auth(token)
+                })
+                .await?;
+
+            items.extend(page.items);
+            match page.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(items),
+            }
+        }
+    }
+
+    /// Title and item count of a playlist, or `None` if there is no such playlist.
+    async fn playlist_details(&mut self, playlist_id: &str) -> Result<Option<PlaylistResponse>, TubeError> {
+        if !self.is_authenticated() {
+            self.authenticate().await?;
+        }
+
+        let url = self.api.endpoint(PLAYLISTS_PATH);
+        let playlists: PlaylistListResponse = self
+            .json_authorized(ApiCall::PlaylistsList, |token| {
+                self.api
+                    .client()
+                    .get(&url)
+                    .query(&[("part", "snippet,contentDetails"), ("id", playlist_id)])
+                    .bearer_auth(token)
+            })
+            .await?;
+        Ok(playlists.items.into_iter().next())
+    }
+
+    /// Pages through our own playlists looking for one with this title.
+    async fn find_playlist_by_title(&mut self, title: &str) -> Result<Option<String>, TubeError> {
+        if !self.is_authenticated() {
+            self.authenticate().await?;
+        }
+
+        let url = self.api.endpoint(PLAYLISTS_PATH);
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut query = vec![("part", "snippet"), ("mine", "true"), ("maxResults", PLAYLISTS_PAGE_SIZE)];
+            if let Some(token) = &page_token {
+                query.push(("pageToken", token.as_str()));
+            }
+            let playlists: PlaylistListResponse = self
+                .json_authorized(ApiCall::PlaylistsList, |token| {
+                    self.api.client().get(&url).query(&query).bearer_auth(token)
+                })
+                .await?;
+
+            let found = playlists.items.into_iter().find(|playlist| {
+                playlist
+                    .snippet
+                    .as_ref()
+                    .and_then(|snippet| snippet.title.as_deref())
+                    == Some(title)
+            });
+            if let Some(playlist) = found {
+                return Ok(Some(playlist.id));
+            }
+            match playlists.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(None),
+            }
         }
     }
 
@@ -124,164 +649,831 @@ impl Tube {
         &mut self,
         playlist_title: &str,
         playlist_description: &str,
-    ) -> Option<String> {
+    ) -> Result<String, TubeError> {
        
-        if self.token.is_none() {
-            self.authenticate().await;
+        if !self.is_authenticated() {
+            self.authenticate().await?;
         }
 
         let mut playlist = Playlist::default();
         playlist.snippet.title = Some(String::from(playlist_title));
         playlist.snippet.description = Some(String::from(playlist_description));
-        playlist.status.privacy_status = Some(String::from("private"));
+        playlist.status.privacy_status = Some(String::from(self.privacy.as_str()));
 
-        let token_str = self.token.as_ref().unwrap().as_str();
+        let url = self.api.endpoint(PLAYLISTS_PATH);
 
-        let result = self
-            .client
-            .post(PLAYLISTS_URI)
-            .query(&[("part", "snippet,status")])
-            .bearer_auth(token_str)
-            .json(&playlist)
-            .send()
-            .await;
+        let playlist_result: PlaylistResponse = self
+            .json_authorized(ApiCall::PlaylistsInsert, |token| {
+                self.api
+                    .client()
+                    .post(&url)
+                    .query(&[("part", "snippet,status")])
+                    .bearer_auth(token)
+                    .json(&playlist)
+            })
+            .await?;
 
-        let response = match result {
-            Ok(res) => res,
-            Err(err) => {
-                error!("Error: failed to instert playlist. {:?}", err);
-                return None;
-            }
+        info!("{:?}", playlist_result);
+        Ok(playlist_result.id)
+    }
+
+    /// Searches for the track and returns the best ranked candidate, or
+    /// `NoResults` when no candidate reaches the configured minimum confidence.
+    /// Falls back through the normalized query variants, while quota allows.
+    async fn find_video_id_for_track(&mut self, track: &TubeTrack) -> Result<VideoMatch, TubeError> {
+        let api_key: String = match &self.api_key {
+            Some(secret) => secret.clone(),
+            None => return Err(TubeError::Auth(format!("{API_KEY_VAR} is not set"))),
         };
 
-        if response.error_for_status_ref().is_ok() {
-            let playlist_result: Result<PlaylistResponse, reqwest::Error> = response.json().await;
-            match playlist_result {
-                Ok(playlist_result) => {
-                    info!("{:?}", playlist_result);
-                    return Some(playlist_result.id);
-                }
-                Err(e) => {
-                    error!("Error: failed to parse playlist response: {:?}", e);
-                    return None;
+        let normalized = NormalizedTrack::new(&track.artist, &track.title);
+        let variants = normalized.query_variants();
+        for (attempt, variant) in variants.iter().take(self.search_variants).enumerate() {
+            if attempt > 0 && !self.quota().lock().unwrap().allows(ApiCall::Search.cost(), self.priority) {
+                info!("Tube:: Not enough quota left to search for {} by {} again", track.title, track.artist);
+                break;
+            }
+            let wanted = TubeTrack {
+                title: variant.title.clone(),
+                ..track.clone()
+            };
+            match self.search_best_match(&wanted, &variant.query, &api_key).await? {
+                Some(best) if best.confidence >= self.min_match_confidence => {
+                    info!(
+                        "Tube:: Matched {} by {} to {} ({:.2}) searching {:?}",
+                        track.title, track.artist, best.video_id, best.confidence, variant.query
+                    );
+                    return Ok(best);
                 }
+                Some(best) => warn!(
+                    "Tube:: Unmatched {} by {} searching {:?} - best candidate {:?} by {} scored {:.2}",
+                    track.title, track.artist, variant.query, best.title, best.channel_title, best.confidence
+                ),
+                None => warn!("Tube:: No results searching {:?}", variant.query),
             }
-        } else {
-            let err: GoogleErrorResponse = response.json().await.unwrap();
-            error!("{:?}", err);
-            return None;
         }
+        Err(TubeError::NoResults {
+            artist: track.artist.clone(),
+            title: track.title.clone(),
+        })
     }
 
-    async fn find_video_id_for_track(&mut self, track: &TubeTrack) -> Option<String> {
-        let search_request = SearchRequestBuilder {
-            query: Some(format!("{} {}", track.title, track.artist)),
+    /// Runs one search and ranks its results against the track.
+    async fn search_best_match(
+        &mut self,
+        track: &TubeTrack,
+        query: &str,
+        api_key: &str,
+    ) -> Result<Option<VideoMatch>, TubeError> {
+        let request = SearchRequestBuilder {
+            query: Some(String::from(query)),
             channel_id: None,
-        };
+            max_results: Some(self.search_max_results),
+            filters: self.search_filters.clone(),
+        }
+        .build(api_key);
+        let url = self.api.endpoint(SEARCH_PATH);
+        let mut search_result: SearchResponse = self
+            .api
+            .json(ApiCall::Search, || self.api.client().get(&url).query(&request))
+            .await?;
+        search_result.items.retain(|item| match self.video_filter.rejection(&item.snippet) {
+            Some(reason) => {
+                info!(
+                    "Tube:: Rejected {:?} by {} ({}): {}",
+                    item.snippet.title,
+                    item.snippet.channel_title,
+                    item.id.clone().into_inner(),
+                    reason
+                );
+                false
+            }
+            None => true,
+        });
 
-        let api_key: String = match env::var(API_KEY_VAR) {
-            Ok(secret) => secret,
-            Err(e) => {
-                trace!("{API_KEY_VAR} {e}");
-                return None;
+        // Only spend quota on video lengths when there is something to compare them to.
+        let durations = match track.duration_secs {
+            Some(_) => {
+                let ids: Vec<String> = search_result
+                    .items
+                    .iter()
+                    .map(|item| item.id.clone().into_inner())
+                    .collect();
+                self.video_durations(&ids).await
             }
+            None => HashMap::new(),
         };
+        Ok(matcher::best_match(
+            track,
+            &search_result.items,
+            &durations,
+            self.duration_tolerance,
+        ))
+    }
 
-        let request = search_request.build(api_key);
-        let result = self.client.get(SEARCH_URI).query(&request).send().await;
+    /// Looks up the lengths of the given videos with videos.list. Videos that
+    /// cannot be found or parsed are left out of the result.
+    async fn video_durations(&mut self, video_ids: &[String]) -> HashMap<String, Duration> {
+        let mut durations = HashMap::new();
+        let api_key = match &self.api_key {
+            Some(secret) => secret.clone(),
+            None => return durations,
+        };
+        if video_ids.is_empty() {
+            return durations;
+        }
 
-        let response = match result {
-            Ok(res) => res,
-            Err(err) => {
-                error!("Error: failed to get search results. {:?}", err);
-                return None;
+        let ids = video_ids.join(",");
+        let url = self.api.endpoint(VIDEOS_PATH);
+        let result: Result<VideoListResponse, ApiError> = self
+            .api
+            .json(ApiCall::VideosList, || {
+                self.api
+                    .client()
+                    .get(&url)
+                    .query(&[("part", "contentDetails"), ("id", ids.as_str()), ("key", api_key.as_str())])
+            })
+            .await;
+
+        match result {
+            Ok(videos) => {
+                for video in videos.items {
+                    if let Some(duration) = video.content_details.and_then(|details| details.duration()) {
+                        durations.insert(video.id, duration);
+                    }
+                }
             }
+            Err(e) => error!("Error: failed to get video details. {}", e),
+        }
+        durations
+    }
+
+    async fn add_video_to_playlist(&mut self, playlist_id: &str, video_id: &str) -> Result<(), TubeError> {
+        let playlist_video = PlaylistItem::new(String::from(playlist_id), String::from(video_id));
+        self.write_playlist_item(ApiCall::PlaylistItemsInsert, &playlist_video).await?;
+        info!("Added video {} to playlist {}", video_id, playlist_id);
+        Ok(())
+    }
+
+    /// Inserts a video at a zero-based position, moving later items down.
+    pub async fn insert_video_at(
+        &mut self,
+        playlist_id: &str,
+        video_id: &str,
+        position: u32,
+    ) -> Result<PlaylistItemResponse, TubeError> {
+        let playlist_video =
+            PlaylistItem::new(String::from(playlist_id), String::from(video_id)).with_position(position);
+        let item = self.write_playlist_item(ApiCall::PlaylistItemsInsert, &playlist_video).await?;
+        info!("Inserted video {} at {} in playlist {}", video_id, position, playlist_id);
+        Ok(item)
+    }
+
+    /// Moves an item to a zero-based position in its playlist.
+    pub async fn move_playlist_item(
+        &mut self,
+        item: &PlaylistItemResponse,
+        position: u32,
+    ) -> Result<PlaylistItemResponse, TubeError> {
+        let playlist_video = PlaylistItem::new(item.playlist_id().to_string(), item.video_id().to_string())
+            .with_id(item.id.clone())
+            .with_position(position);
+        let moved = self.write_playlist_item(ApiCall::PlaylistItemsUpdate, &playlist_video).await?;
+        info!(
+            "Moved video {} from {:?} to {} in playlist {}",
+            item.video_id(),
+            item.position(),
+            position,
+            item.playlist_id()
+        );
+        Ok(moved)
+    }
+
+    pub async fn remove_playlist_item(&mut self, item: &PlaylistItemResponse) -> Result<(), TubeError> {
+        self.delete_playlist_item(item, true).await
+    }
+
+    /// Deletes a playlist item. Unless it was the `last_copy` of its video
+    /// the video stays in the playlist, so dedup keeps knowing about it.
+    async fn delete_playlist_item(&mut self, item: &PlaylistItemResponse, last_copy: bool) -> Result<(), TubeError> {
+        if !self.is_authenticated() {
+            self.authenticate().await?;
+        }
+
+        let url = self.api.endpoint(PLAYLIST_ITEMS_PATH);
+        self.execute_authorized(ApiCall::PlaylistItemsDelete, |token| {
+            self.api
+                .client()
+                .delete(&url)
+                .query(&[("id", item.id.as_str())])
+                .bearer_auth(token)
+        })
+        .await?;
+
+        if last_copy && self.is_own_playlist(item.playlist_id()) {
+            self.playlist_videos.remove(item.video_id());
+            self.playlist_tracks.retain(|_, video_id| video_id != item.video_id());
+        }
+        if self.playlist_id.as_deref() == Some(item.playlist_id()) {
+            self.playlist_item_count = self.playlist_item_count.saturating_sub(1);
+        }
+        info!("Removed video {} from playlist {}", item.video_id(), item.playlist_id());
+        Ok(())
+    }
+
+    /// Sends a playlistItems.insert (POST) or playlistItems.update (PUT).
+    async fn write_playlist_item(
+        &mut self,
+        call: ApiCall,
+        playlist_video: &PlaylistItem,
+    ) -> Result<PlaylistItemResponse, TubeError> {
+        if !self.is_authenticated() {
+            self.authenticate().await?;
+        }
+
+        let url = self.api.endpoint(PLAYLIST_ITEMS_PATH);
+        let item = self
+            .json_authorized(call, |token| {
+                let request = match call {
+                    ApiCall::PlaylistItemsUpdate => self.api.client().put(&url),
+                    _ => self.api.client().post(&url),
+                };
+                request
+                    .query(&[("part", "snippet")])
+                    .bearer_auth(token)
+                    .json(playlist_video)
+            })
+            .await?;
+        if call == ApiCall::PlaylistItemsInsert && self.playlist_id.as_deref() == Some(playlist_video.playlist_id()) {
+            self.playlist_item_count += 1;
+        }
+        Ok(item)
+    }
+
+    /// The title of the playlist this tube fills, once it is known.
+    pub fn playlist_title(&self) -> Option<&str> {
+        if self.playlist_id.is_some() {
+            return Some(&self.playlist_title);
+        }
+        match &self.playlist_target {
+            PlaylistTarget::Title(title) => Some(title),
+            _ => None,
+        }
+    }
+
+    /// The video a track was added to the playlist as, if it is in there.
+    pub fn playlist_video(&self, track: &TubeTrack) -> Option<&String> {
+        self.playlist_tracks.get(&MatchCache::key(&track.artist, &track.title))
+    }
+
+    /// Makes the playlist hold exactly these videos in this order: removes
+    /// the others and any second copies, moves the ones out of place and
+    /// inserts the missing ones. Changes nothing if the quota cannot cover
+    /// all of it.
+    pub async fn arrange_playlist(&mut self, video_ids: &[String]) -> Result<(), TubeError> {
+        let playlist_id = match self.playlist_id.clone() {
+            Some(playlist_id) => playlist_id,
+            None => return Ok(()),
         };
 
-        if response.error_for_status_ref().is_ok() {
-            let search_result: Result<SearchResponse, reqwest::Error> = response.json().await;
-            match search_result {
-                Ok(search_result) => {
-                    let items = search_result.items;
-                    let id = items.first().map(|item| &item.id).unwrap();
-                    let video_id = &id.clone().into_inner();
-                    return Some(video_id.into());
+        let mut items = Vec::new();
+        let mut stale = Vec::new();
+        let mut duplicates = Vec::new();
+        let mut kept = HashSet::new();
+        for item in self.list_playlist_items(&playlist_id).await? {
+            if !video_ids.iter().any(|video_id| video_id == item.video_id()) {
+                stale.push(item);
+            } else if kept.insert(item.video_id().to_string()) {
+                items.push(item);
+            } else {
+                duplicates.push(item);
+            }
+        }
+
+        let current: Vec<&str> = items.iter().map(|item| item.video_id()).collect();
+        let (moves, inserts) = rearrangement(current, video_ids);
+        let cost = (stale.len() + duplicates.len()) as u64 * ApiCall::PlaylistItemsDelete.cost()
+            + moves * ApiCall::PlaylistItemsUpdate.cost()
+            + inserts * ApiCall::PlaylistItemsInsert.cost();
+        if !self.quota().lock().unwrap().allows(cost, self.priority) {
+            return Err(TubeError::Api {
+                status: 403,
+                reason: ErrorReason::QuotaExceeded,
+                message: format!("Not enough quota left to arrange the playlist, it needs {} units", cost),
+            });
+        }
+
+        for item in &stale {
+            self.remove_playlist_item(item).await?;
+        }
+        // The first copy stays, so these leave the video in the playlist.
+        for item in &duplicates {
+            self.delete_playlist_item(item, false).await?;
+        }
+
+        // `items` mirrors the playlist as we change it.
+        for (position, video_id) in video_ids.iter().enumerate() {
+            if items.get(position).map(|item| item.video_id()) == Some(video_id.as_str()) {
+                continue;
+            }
+            let item = match later_position(items.iter().map(|item| item.video_id()), position, video_id) {
+                Some(index) => {
+                    let item = items.remove(index);
+                    self.move_playlist_item(&item, position as u32).await?
                 }
-                Err(e) => {
-                    error!("Error: failed to parse search results: {:?}", e);
-                    None
+                None => {
+                    let item = self.insert_video_at(&playlist_id, video_id, position as u32).await?;
+                    self.playlist_videos.insert(video_id.clone());
+                    item
                 }
-            }
-        } else {
-            let err: GoogleErrorResponse = response.json().await.unwrap();
-            error!("{:?}", err);
-            return None;
+            };
+            items.insert(position, item);
         }
+        Ok(())
     }
+}
 
-    async fn add_video_to_playlist(&mut self, playlist_id: &str, video_id: &str) {
-        if self.token.is_none() {
-            self.authenticate().await;
-        }
+/// Where `video_id` is in the playlist after `position`, if it is.
+fn later_position<'a>(playlist: impl Iterator<Item = &'a str>, position: usize, video_id: &str) -> Option<usize> {
+    playlist
+        .skip(position + 1)
+        .position(|item| item == video_id)
+        .map(|offset| position + 1 + offset)
+}
 
-        let playlist_video = PlaylistItem::new(String::from(playlist_id), String::from(video_id));
-        let token_str = self.token.as_ref().unwrap().as_str();
-
-        let res = self
-            .client
-            .post(PLAYLIST_ITEMS_URI)
-            .query(&[("part", "snippet")])
-            .bearer_auth(token_str)
-            .json(&playlist_video)
-            .send()
-            .await;
-        match res {
-            // todo - might want to extract the response here and return it
-            Ok(_) => info!("Added video successfully"),
-            Err(e) => error!("Error: {}", e),
+/// The moves and inserts `arrange_playlist` makes to turn the playlist into
+/// `video_ids`.
+fn rearrangement<'a>(mut playlist: Vec<&'a str>, video_ids: &'a [String]) -> (u64, u64) {
+    let (mut moves, mut inserts) = (0, 0);
+    for (position, video_id) in video_ids.iter().enumerate() {
+        if playlist.get(position) == Some(&video_id.as_str()) {
+            continue;
         }
+        match later_position(playlist.iter().copied(), position, video_id) {
+            Some(index) => {
+                playlist.remove(index);
+                moves += 1;
+            }
+            None => inserts += 1,
+        }
+        playlist.insert(position, video_id);
     }
+    (moves, inserts)
+}
+
+#[cfg(test)]
+fn test_tube(fake: &crate::fake_tube::FakeTube) -> Tube {
+    let config = fake.config();
+    let match_cache = crate::match_cache::MatchCache::in_memory(config.match_cache_ttl()).shared();
+    let quota = crate::quota::QuotaLedger::in_memory(config.quota_budget(), config.quota_reserve()).shared();
+    Tube::new(&config, match_cache, quota)
 }
 
 #[tokio::test]
 async fn test_process_track() {
-    let track = TubeTrack {
-        id: String::from("id"),
-        title: String::from("title"),
-        artist: String::from("artist"),
-        video_id: None,
-    };
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+
+    let mut tube = test_tube(&fake);
+    let (title, description) = (String::from("test"), String::from("test"));
+    let video_id = tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(video_id.as_deref(), Some("JGwWNGJdvx8"));
 
-    let mut tube = Tube::new();
-    let (title, description) = Tube::generate_sonotube_title_and_description("test");
-    tube.process_track(&track, &title, &description).await;
+    let playlist_id = tube.playlist_id.clone().unwrap();
+    assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["JGwWNGJdvx8"]);
 }
 
 #[tokio::test]
 async fn test_find_video_id_for_track() {
-    let track = TubeTrack {
-        id: String::from("id"),
-        title: String::from("shape of you"),
-        artist: String::from("ed shiran"),
-        video_id: None,
-    };
-    let mut tube = Tube::new();
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed shiran");
+    let mut tube = test_tube(&fake);
     let res = tube.find_video_id_for_track(&track).await;
     info!("{:?}", res);
-    assert!(res.is_some());
-    assert_eq!(res.unwrap(), "JGwWNGJdvx8");
+    assert_eq!(res.unwrap().video_id, "JGwWNGJdvx8");
+}
+
+#[tokio::test]
+async fn test_process_track_uses_match_cache() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+
+    let mut tube = test_tube(&fake);
+    let (title, description) = (String::from("test"), String::from("test"));
+    tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(fake.call_count("search"), 1);
+
+    // A later run shares the cache but not the in-memory dedup set.
+    let mut tube = Tube::new(&fake.config(), tube.match_cache(), tube.quota());
+    let video_id = tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(video_id.as_deref(), Some("JGwWNGJdvx8"));
+    assert_eq!(fake.call_count("search"), 1);
+}
+
+#[tokio::test]
+async fn test_process_track_honors_overrides() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let overrides = Overrides::in_memory().shared();
+    overrides
+        .lock()
+        .unwrap()
+        .set_uri("uri:shape", Override::Video(String::from("kAr40kE0Shp")))
+        .unwrap();
+    overrides
+        .lock()
+        .unwrap()
+        .set_track("Dua Lipa", "Houdini", Override::Skip)
+        .unwrap();
+
+    let mut tube = test_tube(&fake).with_overrides(overrides);
+    let video_id = tube
+        .process_track(&test_track("uri:shape", "Shape of You", "Ed Sheeran"), "test", "test")
+        .await
+        .unwrap();
+    assert_eq!(video_id.as_deref(), Some("kAr40kE0Shp"));
+    let video_id = tube
+        .process_track(&test_track("uri:houdini", "houdini", "dua lipa"), "test", "test")
+        .await
+        .unwrap();
+    assert_eq!(video_id, None);
+    assert_eq!(fake.call_count("search"), 0);
+
+    let playlist_id = tube.playlist_id.clone().unwrap();
+    assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["kAr40kE0Shp"]);
+}
+
+#[tokio::test]
+async fn test_process_track_defers_when_quota_is_low() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let config = fake.config();
+    let match_cache = crate::match_cache::MatchCache::in_memory(config.match_cache_ttl()).shared();
+    // Enough for one new track (50 + 50 + 100 + 1), but the second would eat into the reserve.
+    let quota = crate::quota::QuotaLedger::in_memory(400, 100).shared();
+    let mut tube = Tube::new(&config, match_cache, quota.clone());
+
+    let (title, description) = (String::from("test"), String::from("test"));
+    let first = tube.process_track(&test_track("1", "shape of you", "ed sheeran"), &title, &description).await;
+    assert!(first.unwrap().is_some());
+    assert_eq!(quota.lock().unwrap().usage().used, 200);
+
+    let second = tube.process_track(&test_track("2", "houdini", "dua lipa"), &title, &description).await;
+    assert!(second.unwrap().is_none());
+    assert_eq!(fake.call_count("search"), 1);
+
+    // Urgent work may dip into the reserve.
+    let mut tube = tube.with_priority(Priority::Urgent);
+    tube.process_deferred(&title, &description).await;
+    assert_eq!(fake.call_count("search"), 2);
+    assert_eq!(quota.lock().unwrap().usage().used, 350);
+}
+
+#[tokio::test]
+async fn test_find_video_id_for_track_prefers_matching_duration() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let mut track = test_track("id", "Interstellar", "Deep Forest & Gaudi");
+    let mut tube = test_tube(&fake);
+    let res = tube.find_video_id_for_track(&track).await;
+    assert_eq!(res.unwrap().video_id, "1nt3rXt3nd3");
+
+    track.duration_secs = Some(290);
+    let res = tube.find_video_id_for_track(&track).await;
+    assert_eq!(res.unwrap().video_id, "1nt3rst3ll4");
+}
+
+#[tokio::test]
+async fn test_process_track_skips_unconfident_match() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "bohemian rhapsody", "queen");
+
+    let mut tube = test_tube(&fake);
+    let (title, description) = (String::from("test"), String::from("test"));
+    let res = tube.process_track(&track, &title, &description).await;
+    assert!(matches!(res, Err(TubeError::NoResults { .. })));
+
+    let playlist_id = tube.playlist_id.clone().unwrap();
+    assert!(fake.playlist_video_ids(&playlist_id).is_empty());
+
+    // Without a usable match the track is not searched for again.
+    let res = tube.process_track(&track, &title, &description).await;
+    assert!(res.unwrap().is_none());
+    assert_eq!(fake.call_count("search"), 1);
+}
+
+#[tokio::test]
+async fn test_process_track_reports_auth_errors() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+
+    let mut tube = test_tube(&fake);
+    let (title, description) = (String::from("test"), String::from("test"));
+    fake.fail_next("playlists.insert", 401, "authError", 1);
+    let res = tube.process_track(&track, &title, &description).await;
+    assert!(matches!(res, Err(TubeError::Auth(_))));
+    assert!(tube.playlist_id.is_none());
+    assert!(!tube.seen.contains(&track.id));
+
+    let video_id = tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(video_id.as_deref(), Some("JGwWNGJdvx8"));
+}
+
+#[tokio::test]
+async fn test_process_track_refreshes_rejected_token() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+
+    let mut tube = test_tube(&fake);
+    tube.static_token = None;
+    tube.oauth = fake.oauth_settings("refresh_test");
+    let (title, description) = (String::from("test"), String::from("test"));
+    fake.fail_next("playlistItems.insert", 401, "authError", 1);
+    let video_id = tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(video_id.as_deref(), Some("JGwWNGJdvx8"));
+    assert_eq!(fake.call_count("token"), 1);
+    assert_eq!(fake.call_count("playlistItems.insert"), 2);
+
+    // A second rejection right after a refresh is reported, not retried again.
+    fake.fail_next("playlists.list", 401, "authError", 2);
+    let res = tube.playlist_details("PLnone").await;
+    assert!(matches!(res, Err(TubeError::Auth(_))));
+    assert_eq!(fake.call_count("token"), 2);
+}
+
+#[tokio::test]
+async fn test_search_filters() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+
+    let mut tube = test_tube(&fake);
+    tube.set_search_filters(SearchFilters {
+        channel_id: Some(String::from("UCEdSheeran")),
+        video_category_id: Some(String::from("10")),
+        ..Default::default()
+    });
+    let (title, description) = (String::from("test"), String::from("test"));
+    let video_id = tube
+        .process_track(&test_track("1", "shape of you", "ed sheeran"), &title, &description)
+        .await
+        .unwrap();
+    assert_eq!(video_id.as_deref(), Some("JGwWNGJdvx8"));
+    assert_eq!(fake.last_search_param("channelId").as_deref(), Some("UCEdSheeran"));
+    assert_eq!(fake.last_search_param("videoCategoryId").as_deref(), Some("10"));
+    assert_eq!(fake.last_search_param("regionCode"), None);
+
+    // Nothing by Dua Lipa on Ed Sheeran's channel.
+    let res = tube.process_track(&test_track("2", "houdini", "dua lipa"), &title, &description).await;
+    assert!(matches!(res, Err(TubeError::NoResults { .. })));
+}
+
+#[tokio::test]
+async fn test_search_falls_back_to_query_variants() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "Interstellar (Brazil/France)", "Deep Forest & Gaudi");
+
+    // "Brazil France" is in no video title, so the first query scores too low.
+    let mut tube = test_tube(&fake);
+    let (title, description) = (String::from("test"), String::from("test"));
+    let video_id = tube.process_track(&track, &title, &description).await.unwrap();
+    assert!(video_id.unwrap().starts_with("1nt3r"));
+    assert_eq!(fake.call_count("search"), 2);
+    assert_eq!(fake.last_search_param("q").as_deref(), Some("Interstellar Deep Forest Gaudi"));
+}
+
+#[tokio::test]
+async fn test_video_filter_rejects_candidates() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+
+    let mut tube = test_tube(&fake);
+    let filter = serde_json::json!({ "denyChannelIds": ["UCEdSheeran"], "denyTitles": ["karaoke"] });
+    tube.video_filter = VideoFilter::new(&serde_json::from_value(filter).unwrap()).unwrap();
+
+    // Both Shape of You uploads are rejected, leaving nothing to match.
+    let (title, description) = (String::from("test"), String::from("test"));
+    let res = tube.process_track(&track, &title, &description).await;
+    assert!(matches!(res, Err(TubeError::NoResults { .. })));
+}
+
+#[tokio::test]
+async fn test_process_track_reuses_playlist() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+    let (title, description) = (String::from("test"), String::from("test"));
+
+    // Enough playlists that the one we want is on the second page.
+    for i in 0..60 {
+        fake.add_playlist(&format!("other {i}"));
+    }
+    let existing = fake.add_playlist("sonotube");
+    let mut tube = test_tube(&fake).with_playlist(PlaylistTarget::Title(String::from("sonotube")));
+    tube.process_track(&track, &title, &description).await.unwrap();
+    // Two pages of playlists, then the item count of the one we found.
+    assert_eq!(fake.call_count("playlists.list"), 3);
+    assert_eq!(fake.call_count("playlists.insert"), 0);
+    assert_eq!(fake.playlist_video_ids(&existing), vec!["JGwWNGJdvx8"]);
+
+    let mut tube = test_tube(&fake).with_playlist(PlaylistTarget::Id(existing.clone()));
+    let houdini = test_track("id2", "houdini", "dua lipa");
+    tube.process_track(&houdini, &title, &description).await.unwrap();
+    assert_eq!(fake.call_count("playlists.list"), 4);
+    assert_eq!(fake.playlist_video_ids(&existing), vec!["JGwWNGJdvx8", "suAR1PYFNYA"]);
+
+    let mut tube = test_tube(&fake).with_playlist(PlaylistTarget::Title(String::from("sonotube 2")));
+    tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(fake.call_count("playlists.insert"), 1);
+    let created = tube.playlist_id.clone().unwrap();
+    assert_eq!(fake.playlist_title(&created).as_deref(), Some("sonotube 2"));
+}
+
+#[tokio::test]
+async fn test_process_track_skips_tracks_already_in_playlist() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let (title, description) = (String::from("test"), String::from("test"));
+    let playlist_id = fake.add_playlist("sonotube");
+    let target = PlaylistTarget::Id(playlist_id.clone());
+
+    let mut tube = test_tube(&fake).with_playlist(target.clone());
+    tube.process_track(&test_track("1", "shape of you", "ed sheeran"), &title, &description)
+        .await
+        .unwrap();
+    tube.process_track(&test_track("2", "houdini", "dua lipa"), &title, &description)
+        .await
+        .unwrap();
+    assert_eq!(fake.call_count("search"), 2);
+
+    // A restart forgets `seen` but finds both tracks in the playlist, without searching.
+    let mut tube = Tube::new(&fake.config(), tube.match_cache(), tube.quota()).with_playlist(target);
+    let res = tube.process_track(&test_track("1", "shape of you", "ed sheeran"), &title, &description).await;
+    assert!(res.unwrap().is_none());
+    let res = tube.process_track(&test_track("3", "Houdini", "Dua Lipa"), &title, &description).await;
+    assert!(res.unwrap().is_none());
+    assert_eq!(fake.call_count("search"), 2);
+    assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["JGwWNGJdvx8", "suAR1PYFNYA"]);
+
+    // Without a cached match the video id still catches the duplicate after a search.
+    let mut tube = test_tube(&fake).with_playlist(PlaylistTarget::Id(playlist_id.clone()));
+    let res = tube.process_track(&test_track("1", "shape of you", "ed sheeran"), &title, &description).await;
+    assert!(res.unwrap().is_none());
+    assert_eq!(fake.playlist_video_ids(&playlist_id).len(), 2);
+}
+
+#[tokio::test]
+async fn test_playlist_item_management() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let mut tube = test_tube(&fake);
+    let playlist_id = tube.insert_playlist("test", "test").await.unwrap();
+    for video_id in ["JGwWNGJdvx8", "WA4iX5D9Z64", "suAR1PYFNYA"] {
+        tube.add_video_to_playlist(&playlist_id, video_id).await.unwrap();
+    }
+
+    let items = tube.list_playlist_items(&playlist_id).await.unwrap();
+    let positions: Vec<Option<u32>> = items.iter().map(|item| item.position()).collect();
+    assert_eq!(positions, vec![Some(0), Some(1), Some(2)]);
+
+    let moved = tube.move_playlist_item(&items[2], 0).await.unwrap();
+    assert_eq!(moved.position(), Some(0));
+    assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["suAR1PYFNYA", "JGwWNGJdvx8", "WA4iX5D9Z64"]);
+
+    tube.remove_playlist_item(&items[0]).await.unwrap();
+    let inserted = tube.insert_video_at(&playlist_id, "1nt3rst3ll4", 1).await.unwrap();
+    assert_eq!(inserted.position(), Some(1));
+    assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["suAR1PYFNYA", "1nt3rst3ll4", "WA4iX5D9Z64"]);
+}
+
+#[tokio::test]
+async fn test_arrange_playlist() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let mut tube = test_tube(&fake);
+    let playlist_id = tube.resolve_playlist("test", "test").await.unwrap();
+    for video_id in ["JGwWNGJdvx8", "WA4iX5D9Z64", "suAR1PYFNYA", "b0hEmCov3r1", "JGwWNGJdvx8"] {
+        tube.add_video_to_playlist(&playlist_id, video_id).await.unwrap();
+        // As `process_track` would.
+        tube.playlist_videos.insert(video_id.to_string());
+    }
+
+    let chart: Vec<String> = ["suAR1PYFNYA", "1nt3rst3ll4", "JGwWNGJdvx8", "WA4iX5D9Z64"]
+        .iter()
+        .map(|id| id.to_string())
+        .collect();
+    tube.arrange_playlist(&chart).await.unwrap();
+    assert_eq!(fake.playlist_video_ids(&playlist_id), chart);
+    // The video off the chart and the second copy of a chart video.
+    assert_eq!(fake.call_count("playlistItems.delete"), 2);
+    assert_eq!(fake.call_count("playlistItems.update"), 1);
+    // Only the video that left is forgotten; one copy of the other is still there.
+    assert!(tube.playlist_videos.contains("JGwWNGJdvx8"));
+    assert!(!tube.playlist_videos.contains("b0hEmCov3r1"));
+
+    // Already in order: nothing to do.
+    tube.arrange_playlist(&chart).await.unwrap();
+    assert_eq!(fake.call_count("playlistItems.update"), 1);
+    assert_eq!(fake.call_count("playlistItems.insert"), 6);
+
+    // Without the quota for all of it, nothing changes.
+    tube.quota().lock().unwrap().exhaust();
+    let reversed: Vec<String> = chart.iter().rev().cloned().collect();
+    let err = tube.arrange_playlist(&reversed).await.unwrap_err();
+    assert_eq!(err.reason(), Some(&ErrorReason::QuotaExceeded));
+    assert_eq!(fake.playlist_video_ids(&playlist_id), chart);
+    assert_eq!(fake.call_count("playlistItems.update"), 1);
+}
+
+#[tokio::test]
+async fn test_process_track_rolls_over_full_playlists() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let (title, description) = (String::from("test"), String::from("test"));
+    let first = fake.add_playlist("sonotube");
+    let target = PlaylistTarget::Id(first.clone());
+
+    let mut tube = test_tube(&fake).with_playlist(target.clone());
+    tube.playlist_item_limit = 2;
+    tube.process_track(&test_track("1", "shape of you", "ed sheeran"), &title, &description).await.unwrap();
+    tube.process_track(&test_track("2", "houdini", "dua lipa"), &title, &description).await.unwrap();
+    tube.process_track(&test_track("3", "we are never getting back together", "taylor swift"), &title, &description)
+        .await
+        .unwrap();
+
+    let parts = tube.playlist_parts.parts(&first);
+    assert_eq!(parts.len(), 2);
+    assert_eq!(fake.playlist_title(&parts[1]).as_deref(), Some("sonotube (part 2)"));
+    assert_eq!(fake.playlist_video_ids(&first), vec!["JGwWNGJdvx8", "suAR1PYFNYA"]);
+    assert_eq!(fake.playlist_video_ids(&parts[1]), vec!["WA4iX5D9Z64"]);
+
+    // After a restart dedup covers every part and new tracks go to the last one.
+    let mut tube = Tube::new(&fake.config(), tube.match_cache(), tube.quota())
+        .with_playlist(target)
+        .with_playlist_parts(tube.playlist_parts.clone());
+    tube.playlist_item_limit = 2;
+    let res = tube.process_track(&test_track("2", "houdini", "dua lipa"), &title, &description).await;
+    assert!(res.unwrap().is_none());
+    let res = tube.process_track(&test_track("4", "interstellar", "deep forest & gaudi"), &title, &description).await;
+    assert_eq!(res.unwrap().as_deref(), Some("1nt3rXt3nd3"));
+    assert_eq!(fake.playlist_video_ids(&parts[1]), vec!["WA4iX5D9Z64", "1nt3rXt3nd3"]);
+    assert_eq!(fake.call_count("search"), 4);
+}
+
+#[tokio::test]
+async fn test_process_track_rolls_over_when_youtube_says_full() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+    let (title, description) = (String::from("test"), String::from("test"));
+    let first = fake.add_playlist("sonotube");
+
+    let mut tube = test_tube(&fake).with_playlist(PlaylistTarget::Id(first.clone()));
+    fake.fail_next("playlistItems.insert", 403, "playlistContainsMaximumNumberOfVideos", 1);
+    let video_id = tube.process_track(&track, &title, &description).await.unwrap();
+    assert_eq!(video_id.as_deref(), Some("JGwWNGJdvx8"));
+
+    let parts = tube.playlist_parts.parts(&first);
+    assert!(fake.playlist_video_ids(&first).is_empty());
+    assert_eq!(fake.playlist_video_ids(&parts[1]), vec!["JGwWNGJdvx8"]);
 }
 
 #[tokio::test]
 async fn test_add_video_to_playlist() {
-    let mut tube = Tube::new();
-    tube.add_video_to_playlist("PLtZ7tJkCfjGxIK-bH7fodXCmpEDmEvebL", "JGwWNGJdvx8")
-        .await;
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let mut tube = test_tube(&fake);
+    let playlist_id = tube.insert_playlist("test", "test").await.unwrap();
+    tube.add_video_to_playlist(&playlist_id, "JGwWNGJdvx8")
+        .await
+        .unwrap();
+    assert_eq!(fake.playlist_video_ids(&playlist_id), vec!["JGwWNGJdvx8"]);
+}
+
+#[tokio::test]
+async fn test_add_video_to_playlist_reports_permanent_errors() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let mut tube = test_tube(&fake);
+    let playlist_id = tube.insert_playlist("test", "test").await.unwrap();
+
+    fake.fail_next("playlistItems.insert", 403, "playlistItemsNotAccessible", 1);
+    let err = tube.add_video_to_playlist(&playlist_id, "JGwWNGJdvx8").await.unwrap_err();
+    assert_eq!(err.reason(), Some(&ErrorReason::PlaylistItemsNotAccessible));
+    assert_eq!(fake.call_count("playlistItems.insert"), 1);
+    assert!(fake.playlist_video_ids(&playlist_id).is_empty());
+}
+
+#[tokio::test]
+async fn test_find_video_id_for_track_retries_transient_errors() {
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let track = test_track("id", "shape of you", "ed sheeran");
+    let mut tube = test_tube(&fake);
+
+    fake.fail_next("search", 503, "backendError", 2);
+    let res = tube.find_video_id_for_track(&track).await;
+    assert_eq!(res.unwrap().video_id, "JGwWNGJdvx8");
+    assert_eq!(fake.call_count("search"), 3);
+    assert_eq!(tube.quota().lock().unwrap().usage().used, 300);
+
+    fake.fail_next("search", 403, "quotaExceeded", 1);
+    let err = tube.find_video_id_for_track(&track).await.unwrap_err();
+    assert_eq!(err.reason(), Some(&ErrorReason::QuotaExceeded));
+    assert_eq!(fake.call_count("search"), 4);
+    assert_eq!(tube.quota().lock().unwrap().usage().remaining, 0);
 }
 
 #[tokio::test]
 async fn test_insert_playlist() {
-    let mut tube = Tube::new();
-    let id = tube.insert_playlist("test", "test").await;
-    assert!(id.is_some());
+    let fake = crate::fake_tube::FakeTube::start().await;
+    let mut tube = test_tube(&fake);
+    let id = tube.insert_playlist("test", "test").await.unwrap();
+    assert_eq!(fake.playlist_title(&id).as_deref(), Some("test"));
 }
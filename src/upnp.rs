@@ -0,0 +1,587 @@
+//! UPnP event subscriptions to Sonos AVTransport, so track changes arrive as
+//! they happen instead of whenever the next poll comes around.
+//!
+//! `Subscriber` sends the GENA SUBSCRIBE, renewal and UNSUBSCRIBE requests,
+//! and `EventListener` serves the callback URL speakers NOTIFY. Each
+//! AVTransport NOTIFY carries a `LastChange` document with the transport
+//! state and, when the track changed, its URI and DIDL-Lite metadata.
+//! ZoneGroupTopology events carry the household's zone groups instead.
+
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use log::{info, warn};
+use sonos::{Track, TransportState};
+use std::net::{IpAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use xmltree::Element;
+
+pub const SONOS_PORT: u16 = 1400;
+pub const AVTRANSPORT_EVENT_PATH: &str = "/MediaRenderer/AVTransport/Event";
+pub const TOPOLOGY_EVENT_PATH: &str = "/ZoneGroupTopology/Event";
+pub const TOPOLOGY_CONTROL_PATH: &str = "/ZoneGroupTopology/Control";
+const GET_ZONE_GROUP_STATE: &str = "urn:schemas-upnp-org:service:ZoneGroupTopology:1#GetZoneGroupState";
+const CALLBACK_PATH: &str = "/notify";
+/// How long we ask speakers to keep a subscription. It is renewed halfway.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(1800);
+
+#[derive(Debug)]
+pub enum UpnpError {
+    /// The request never got a response.
+    Transport(reqwest::Error),
+    /// The speaker refused, e.g. 412 for a subscription it already dropped.
+    Status(u16),
+    /// A SUBSCRIBE response without a subscription id.
+    MissingSid,
+    /// A response body was not what we expected.
+    Parse(String),
+}
+
+impl std::fmt::Display for UpnpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpnpError::Transport(e) => write!(f, "request failed: {}", e),
+            UpnpError::Status(status) => write!(f, "speaker answered {}", status),
+            UpnpError::MissingSid => write!(f, "speaker sent no subscription id"),
+            UpnpError::Parse(message) => write!(f, "unexpected response: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for UpnpError {}
+
+/// The URL of one of a speaker's services, e.g. `AVTRANSPORT_EVENT_PATH`.
+pub fn service_url(ip: IpAddr, path: &str) -> String {
+    format!("http://{}:{}{}", ip, SONOS_PORT, path)
+}
+
+/// The address of this machine that the speaker at `ip` can reach us on.
+pub fn local_address_for(ip: IpAddr) -> std::io::Result<IpAddr> {
+    let socket = UdpSocket::bind((if ip.is_ipv4() { "0.0.0.0" } else { "::" }, 0))?;
+    // Connecting a UDP socket sends nothing, it only picks the route.
+    socket.connect((ip, SONOS_PORT))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// What a speaker's AVTransport reported in one NOTIFY. Fields it left out are `None`.
+#[derive(Debug, Default)]
+pub struct TransportEvent {
+    pub transport_state: Option<TransportState>,
+    /// Present when the event names the current track.
+    pub track: Option<Track>,
+}
+
+fn parse_transport_state(state: &str) -> Option<TransportState> {
+    match state {
+        "STOPPED" => Some(TransportState::Stopped),
+        "PLAYING" => Some(TransportState::Playing),
+        "PAUSED_PLAYBACK" => Some(TransportState::PausedPlayback),
+        "PAUSED_RECORDING" => Some(TransportState::PausedRecording),
+        "RECORDING" => Some(TransportState::Recording),
+        "TRANSITIONING" => Some(TransportState::Transitioning),
+        _ => None,
+    }
+}
+
+/// Parses `h:mm:ss`, treating anything else, e.g. `NOT_IMPLEMENTED` for radio, as zero.
+fn parse_duration(duration: &str) -> Duration {
+    let parts: Option<Vec<u64>> = duration.split(':').map(|part| part.parse().ok()).collect();
+    match parts.as_deref() {
+        Some([hours, minutes, seconds]) => Duration::from_secs(hours * 3600 + minutes * 60 + seconds),
+        _ => Duration::ZERO,
+    }
+}
+
+fn child_text(element: &Element, name: &str) -> Option<String> {
+    element
+        .get_child(name)
+        .and_then(|child| child.get_text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// Title, artist and album from a DIDL-Lite document.
+fn parse_metadata(didl: &str) -> Option<(String, String, Option<String>)> {
+    let didl = Element::parse(didl.as_bytes()).ok()?;
+    let item = didl.get_child("item")?;
+    let title = child_text(item, "title")?;
+    let artist = child_text(item, "creator").unwrap_or_default();
+    Some((title, artist, child_text(item, "album")))
+}
+
+/// A zone and the room it plays in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneMember {
+    pub uuid: String,
+    pub room: String,
+}
+
+/// Zones playing together. The coordinator plays, the others follow it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneGroup {
+    /// UUID of the coordinator zone.
+    pub coordinator: String,
+    pub members: Vec<ZoneMember>,
+}
+
+impl ZoneGroup {
+    /// The group's rooms, the coordinator's first. Stereo pairs count once.
+    pub fn rooms(&self) -> Vec<String> {
+        let mut members: Vec<&ZoneMember> = self.members.iter().collect();
+        members.sort_by_key(|member| member.uuid != self.coordinator);
+        let mut rooms: Vec<String> = Vec::new();
+        for member in members {
+            if !rooms.contains(&member.room) {
+                rooms.push(member.room.clone());
+            }
+        }
+        rooms
+    }
+}
+
+/// Parses a `ZoneGroupState` document. Newer speakers wrap the `ZoneGroups`
+/// in it, older ones send them on their own.
+pub fn parse_zone_group_state(state: &str) -> Result<Vec<ZoneGroup>, String> {
+    let state = Element::parse(state.as_bytes()).map_err(|e| format!("ZoneGroupState is not XML: {}", e))?;
+    let groups = match state.name.as_str() {
+        "ZoneGroups" => &state,
+        _ => state
+            .get_child("ZoneGroups")
+            .ok_or_else(|| String::from("ZoneGroupState has no ZoneGroups"))?,
+    };
+    let elements = |element: &Element, name: &str| -> Vec<Element> {
+        element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|child| child.name == name)
+            .cloned()
+            .collect()
+    };
+    let attribute = |element: &Element, name: &str| element.attributes.get(name).cloned().unwrap_or_default();
+    Ok(elements(groups, "ZoneGroup")
+        .iter()
+        .map(|group| ZoneGroup {
+            coordinator: attribute(group, "Coordinator"),
+            members: elements(group, "ZoneGroupMember")
+                .iter()
+                // Subwoofers and surrounds are invisible members of their room.
+                .filter(|member| attribute(member, "Invisible") != "1")
+                .map(|member| ZoneMember {
+                    uuid: attribute(member, "UUID"),
+                    room: attribute(member, "ZoneName"),
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+/// Asks a speaker for the household's zone groups.
+pub async fn zone_groups(control_url: &str) -> Result<Vec<ZoneGroup>, UpnpError> {
+    let envelope = r#"<?xml version="1.0" encoding="utf-8"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:GetZoneGroupState xmlns:u="urn:schemas-upnp-org:service:ZoneGroupTopology:1"></u:GetZoneGroupState></s:Body></s:Envelope>"#;
+    let response = reqwest::Client::new()
+        .post(control_url)
+        .timeout(Duration::from_secs(5))
+        .header("CONTENT-TYPE", "text/xml; charset=\"utf-8\"")
+        .header("SOAPACTION", format!("\"{}\"", GET_ZONE_GROUP_STATE))
+        .body(envelope)
+        .send()
+        .await
+        .map_err(UpnpError::Transport)?;
+    if !response.status().is_success() {
+        return Err(UpnpError::Status(response.status().as_u16()));
+    }
+    let body = response.text().await.map_err(UpnpError::Transport)?;
+    let envelope = Element::parse(body.as_bytes()).map_err(|e| UpnpError::Parse(e.to_string()))?;
+    let state = envelope
+        .get_child("Body")
+        .and_then(|body| body.get_child("GetZoneGroupStateResponse"))
+        .and_then(|response| child_text(response, "ZoneGroupState"))
+        .ok_or_else(|| UpnpError::Parse(String::from("no ZoneGroupState in the response")))?;
+    parse_zone_group_state(&state).map_err(UpnpError::Parse)
+}
+
+/// What one NOTIFY reported.
+#[derive(Debug)]
+pub enum SpeakerEvent {
+    Transport(TransportEvent),
+    Topology(Vec<ZoneGroup>),
+}
+
+/// Parses the body of a NOTIFY from a speaker's AVTransport or
+/// ZoneGroupTopology service. Returns `None` for events we have no use for.
+pub fn parse_notify(body: &str) -> Result<Option<SpeakerEvent>, String> {
+    let propertyset = Element::parse(body.as_bytes()).map_err(|e| format!("event is not XML: {}", e))?;
+    let property = |name: &str| {
+        propertyset
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .find_map(|property| child_text(property, name))
+    };
+    if let Some(last_change) = property("LastChange") {
+        return parse_last_change(&last_change).map(|event| Some(SpeakerEvent::Transport(event)));
+    }
+    if let Some(state) = property("ZoneGroupState") {
+        return parse_zone_group_state(&state).map(|groups| Some(SpeakerEvent::Topology(groups)));
+    }
+    // Topology events also report e.g. available software updates.
+    Ok(None)
+}
+
+fn parse_last_change(last_change: &str) -> Result<TransportEvent, String> {
+    let last_change = Element::parse(last_change.as_bytes()).map_err(|e| format!("LastChange is not XML: {}", e))?;
+    let instance = last_change
+        .get_child("InstanceID")
+        .ok_or_else(|| String::from("LastChange has no InstanceID"))?;
+    let value = |name: &str| {
+        instance
+            .get_child(name)
+            .and_then(|variable| variable.attributes.get("val"))
+            .map(String::as_str)
+    };
+
+    let track = match (
+        value("CurrentTrackURI"),
+        value("CurrentTrackMetaData").and_then(parse_metadata),
+    ) {
+        (Some(uri), Some((title, artist, album))) if !uri.is_empty() => Some(Track {
+            title,
+            artist,
+            album,
+            queue_position: value("CurrentTrack").and_then(|n| n.parse().ok()).unwrap_or(0),
+            uri: uri.to_string(),
+            duration: value("CurrentTrackDuration").map(parse_duration).unwrap_or_default(),
+            running_time: Duration::ZERO,
+        }),
+        _ => None,
+    };
+    Ok(TransportEvent {
+        transport_state: value("TransportState").and_then(parse_transport_state),
+        track,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub sid: String,
+    pub event_url: String,
+    renew_at: Instant,
+}
+
+impl Subscription {
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.renew_at
+    }
+}
+
+/// Parses a GENA `TIMEOUT` header such as `Second-1800`.
+fn parse_timeout(header: Option<&str>) -> Duration {
+    header
+        .and_then(|timeout| timeout.strip_prefix("Second-"))
+        .and_then(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(SUBSCRIPTION_TIMEOUT)
+}
+
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    client: reqwest::Client,
+    callback_url: String,
+}
+
+impl Subscriber {
+    pub fn new(callback_url: &str) -> Self {
+        Subscriber {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .expect("Unable to build the UPnP client"),
+            callback_url: callback_url.to_string(),
+        }
+    }
+
+    async fn send(
+        &self,
+        method: &[u8],
+        event_url: &str,
+        headers: &[(&str, String)],
+    ) -> Result<reqwest::Response, UpnpError> {
+        let method = reqwest::Method::from_bytes(method).unwrap();
+        let mut request = self.client.request(method, event_url);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let response = request.send().await.map_err(UpnpError::Transport)?;
+        match response.status().as_u16() {
+            200 => Ok(response),
+            status => Err(UpnpError::Status(status)),
+        }
+    }
+
+    fn subscription(event_url: &str, sid: String, response: &reqwest::Response) -> Subscription {
+        let timeout = parse_timeout(response.headers().get("TIMEOUT").and_then(|t| t.to_str().ok()));
+        Subscription {
+            sid,
+            event_url: event_url.to_string(),
+            renew_at: Instant::now() + timeout / 2,
+        }
+    }
+
+    pub async fn subscribe(&self, event_url: &str) -> Result<Subscription, UpnpError> {
+        let headers = [
+            ("CALLBACK", format!("<{}>", self.callback_url)),
+            ("NT", String::from("upnp:event")),
+            ("TIMEOUT", format!("Second-{}", SUBSCRIPTION_TIMEOUT.as_secs())),
+        ];
+        let response = self.send(b"SUBSCRIBE", event_url, &headers).await?;
+        let sid = response
+            .headers()
+            .get("SID")
+            .and_then(|sid| sid.to_str().ok())
+            .ok_or(UpnpError::MissingSid)?
+            .to_string();
+        info!("Subscribed to {} as {}", event_url, sid);
+        Ok(Subscriber::subscription(event_url, sid, &response))
+    }
+
+    /// Extends a subscription. A speaker that restarted has forgotten it and
+    /// answers 412, in which case subscribe again.
+    pub async fn renew(&self, subscription: &mut Subscription) -> Result<(), UpnpError> {
+        let headers = [
+            ("SID", subscription.sid.clone()),
+            ("TIMEOUT", format!("Second-{}", SUBSCRIPTION_TIMEOUT.as_secs())),
+        ];
+        let response = self.send(b"SUBSCRIBE", &subscription.event_url, &headers).await?;
+        *subscription = Subscriber::subscription(&subscription.event_url, subscription.sid.clone(), &response);
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, subscription: &Subscription) -> Result<(), UpnpError> {
+        let headers = [("SID", subscription.sid.clone())];
+        self.send(b"UNSUBSCRIBE", &subscription.event_url, &headers).await?;
+        info!("Unsubscribed {}", subscription.sid);
+        Ok(())
+    }
+}
+
+/// An event and the subscription it was sent for.
+#[derive(Debug)]
+pub struct Notification {
+    pub sid: String,
+    pub event: SpeakerEvent,
+}
+
+type Notifications = web::Data<mpsc::UnboundedSender<Notification>>;
+
+async fn notify(request: HttpRequest, body: web::Bytes, notifications: Notifications) -> HttpResponse {
+    if request.method().as_str() != "NOTIFY" {
+        return HttpResponse::MethodNotAllowed().finish();
+    }
+    let sid = match request.headers().get("SID").and_then(|sid| sid.to_str().ok()) {
+        Some(sid) => sid.to_string(),
+        None => return HttpResponse::PreconditionFailed().finish(),
+    };
+    match parse_notify(&String::from_utf8_lossy(&body)) {
+        Ok(Some(event)) => {
+            // The monitor may be shutting down, in which case nobody listens.
+            let _ = notifications.send(Notification { sid, event });
+            HttpResponse::Ok().finish()
+        }
+        Ok(None) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            warn!("Ignoring event for {}: {}", sid, e);
+            HttpResponse::BadRequest().finish()
+        }
+    }
+}
+
+/// Serves the callback URL speakers send their events to.
+pub struct EventListener {
+    pub callback_url: String,
+    notifications: mpsc::UnboundedReceiver<Notification>,
+    handle: ServerHandle,
+}
+
+impl EventListener {
+    /// Listens on `ip`, on `port` or any free port if it is 0.
+    pub fn start(ip: IpAddr, port: u16) -> std::io::Result<EventListener> {
+        let (sender, notifications) = mpsc::unbounded_channel();
+        let sender = web::Data::new(sender);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(sender.clone())
+                .route(CALLBACK_PATH, web::route().to(notify))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind((ip, port))?;
+
+        let port = server.addrs()[0].port();
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let host = match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        };
+        let callback_url = format!("http://{}:{}{}", host, port, CALLBACK_PATH);
+        info!("Listening for Sonos events on {}", callback_url);
+        Ok(EventListener {
+            callback_url,
+            notifications,
+            handle,
+        })
+    }
+
+    /// The next event, or `None` if none arrives within `timeout`.
+    pub async fn next(&mut self, timeout: Duration) -> Option<Notification> {
+        tokio::time::timeout(timeout, self.notifications.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(true).await;
+    }
+}
+
+#[cfg(test)]
+const NOTIFY_BODY: &str = r#"<e:propertyset xmlns:e="urn:schemas-upnp-org:event-1-0"><e:property><LastChange>&lt;Event xmlns=&quot;urn:schemas-upnp-org:metadata-1-0/AVT/&quot; xmlns:r=&quot;urn:schemas-rinconnetworks-com:metadata-1-0/&quot;&gt;&lt;InstanceID val=&quot;0&quot;&gt;&lt;TransportState val=&quot;PLAYING&quot;/&gt;&lt;CurrentPlayMode val=&quot;NORMAL&quot;/&gt;&lt;NumberOfTracks val=&quot;12&quot;/&gt;&lt;CurrentTrack val=&quot;3&quot;/&gt;&lt;CurrentTrackURI val=&quot;x-sonos-spotify:spotify%3atrack%3a6aBUnkXuCEQQHAlTokv9or?sid=12&amp;amp;flags=8224&quot;/&gt;&lt;CurrentTrackDuration val=&quot;0:03:05&quot;/&gt;&lt;CurrentTrackMetaData val=&quot;&amp;lt;DIDL-Lite xmlns:dc=&amp;quot;http://purl.org/dc/elements/1.1/&amp;quot; xmlns:upnp=&amp;quot;urn:schemas-upnp-org:metadata-1-0/upnp/&amp;quot; xmlns=&amp;quot;urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/&amp;quot;&amp;gt;&amp;lt;item id=&amp;quot;-1&amp;quot; parentID=&amp;quot;-1&amp;quot;&amp;gt;&amp;lt;res duration=&amp;quot;0:03:05&amp;quot;&amp;gt;x-sonos-spotify:spotify%3atrack%3a6aBUnkXuCEQQHAlTokv9or?sid=12&amp;amp;amp;flags=8224&amp;lt;/res&amp;gt;&amp;lt;dc:title&amp;gt;Houdini&amp;lt;/dc:title&amp;gt;&amp;lt;dc:creator&amp;gt;Dua Lipa&amp;lt;/dc:creator&amp;gt;&amp;lt;upnp:album&amp;gt;Radical Optimism&amp;lt;/upnp:album&amp;gt;&amp;lt;/item&amp;gt;&amp;lt;/DIDL-Lite&amp;gt;&quot;/&gt;&lt;/InstanceID&gt;&lt;/Event&gt;</LastChange></e:property></e:propertyset>"#;
+
+#[cfg(test)]
+fn transport_event(event: Option<SpeakerEvent>) -> TransportEvent {
+    match event {
+        Some(SpeakerEvent::Transport(event)) => event,
+        other => panic!("expected a transport event, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_notify() {
+    let event = transport_event(parse_notify(NOTIFY_BODY).unwrap());
+    assert_eq!(event.transport_state, Some(TransportState::Playing));
+    let track = event.track.unwrap();
+    assert_eq!(track.title, "Houdini");
+    assert_eq!(track.artist, "Dua Lipa");
+    assert_eq!(track.album.as_deref(), Some("Radical Optimism"));
+    assert_eq!(track.queue_position, 3);
+    assert_eq!(
+        track.uri,
+        "x-sonos-spotify:spotify%3atrack%3a6aBUnkXuCEQQHAlTokv9or?sid=12&flags=8224"
+    );
+    assert_eq!(track.duration, Duration::from_secs(185));
+
+    // Pausing only reports the new state.
+    let paused = NOTIFY_BODY.replace("PLAYING", "PAUSED_PLAYBACK");
+    let paused = regex::Regex::new(r"&lt;CurrentTrackURI.*?/&gt;")
+        .unwrap()
+        .replace(&paused, "");
+    let event = transport_event(parse_notify(&paused).unwrap());
+    assert_eq!(event.transport_state, Some(TransportState::PausedPlayback));
+    assert!(event.track.is_none());
+
+    assert!(parse_notify("<e:propertyset xmlns:e=\"urn:x\"/>").unwrap().is_none());
+    assert!(parse_notify("not xml").is_err());
+}
+
+#[tokio::test]
+async fn test_subscribe_and_receive_events() {
+    let device = crate::fake_upnp::FakeUpnpDevice::start().await;
+    let localhost: IpAddr = [127, 0, 0, 1].into();
+    let mut listener = EventListener::start(localhost, 0).unwrap();
+    let subscriber = Subscriber::new(&listener.callback_url);
+
+    let mut subscription = subscriber.subscribe(&device.url(AVTRANSPORT_EVENT_PATH)).await.unwrap();
+    assert_eq!(device.subscribers(), vec![listener.callback_url.clone()]);
+    assert!(!subscription.is_due());
+
+    // A new subscriber is sent the current state right away.
+    let notification = listener.next(Duration::from_secs(5)).await.unwrap();
+    assert_eq!(notification.sid, subscription.sid);
+    assert_eq!(
+        transport_event(Some(notification.event)).track.unwrap().title,
+        "Houdini"
+    );
+
+    device
+        .play("x-sonos-spotify:shape", "Ed Sheeran", "Shape of You", "0:03:53")
+        .await;
+    let event = transport_event(listener.next(Duration::from_secs(5)).await.map(|n| n.event));
+    assert_eq!(event.transport_state, Some(TransportState::Playing));
+    let track = event.track.unwrap();
+    assert_eq!(
+        (track.artist.as_str(), track.title.as_str()),
+        ("Ed Sheeran", "Shape of You")
+    );
+    assert_eq!(track.duration, Duration::from_secs(233));
+
+    device.pause().await;
+    let event = transport_event(listener.next(Duration::from_secs(5)).await.map(|n| n.event));
+    assert_eq!(event.transport_state, Some(TransportState::PausedPlayback));
+    assert!(event.track.is_none());
+
+    subscriber.renew(&mut subscription).await.unwrap();
+    assert_eq!(device.call_count("renew"), 1);
+
+    subscriber.unsubscribe(&subscription).await.unwrap();
+    assert!(device.subscribers().is_empty());
+    // A speaker that forgot the subscription refuses to renew it.
+    assert!(matches!(
+        subscriber.renew(&mut subscription).await,
+        Err(UpnpError::Status(412))
+    ));
+    assert!(listener.next(Duration::from_millis(100)).await.is_none());
+    listener.stop().await;
+}
+
+#[tokio::test]
+async fn test_zone_groups() {
+    let device = crate::fake_upnp::FakeUpnpDevice::start().await;
+    device
+        .regroup(&[
+            (
+                "RINCON_KITCHEN",
+                &[("RINCON_KITCHEN", "Kitchen"), ("RINCON_OFFICE", "Office")],
+            ),
+            ("RINCON_BEDROOM", &[("RINCON_BEDROOM", "Bedroom")]),
+        ])
+        .await;
+    let groups = zone_groups(&device.url(TOPOLOGY_CONTROL_PATH)).await.unwrap();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].coordinator, "RINCON_KITCHEN");
+    assert_eq!(groups[0].rooms(), vec!["Kitchen", "Office"]);
+    assert_eq!(groups[1].rooms(), vec!["Bedroom"]);
+
+    let mut listener = EventListener::start([127, 0, 0, 1].into(), 0).unwrap();
+    let subscriber = Subscriber::new(&listener.callback_url);
+    subscriber.subscribe(&device.url(TOPOLOGY_EVENT_PATH)).await.unwrap();
+    let topology = |event: Option<Notification>| match event.map(|n| n.event) {
+        Some(SpeakerEvent::Topology(groups)) => groups,
+        other => panic!("expected a topology event, got {:?}", other),
+    };
+    assert_eq!(topology(listener.next(Duration::from_secs(5)).await), groups);
+
+    // The office leaves the kitchen's group and the bedroom joins it.
+    device
+        .regroup(&[
+            (
+                "RINCON_KITCHEN",
+                &[("RINCON_KITCHEN", "Kitchen"), ("RINCON_BEDROOM", "Bedroom")],
+            ),
+            ("RINCON_OFFICE", &[("RINCON_OFFICE", "Office")]),
+        ])
+        .await;
+    let groups = topology(listener.next(Duration::from_secs(5)).await);
+    assert_eq!(groups[0].rooms(), vec!["Kitchen", "Bedroom"]);
+    assert_eq!(groups[1].coordinator, "RINCON_OFFICE");
+
+    // A stereo pair with a subwoofer is one room.
+    let state = r#"<ZoneGroups><ZoneGroup Coordinator="RINCON_L" ID="RINCON_L:1"><ZoneGroupMember UUID="RINCON_L" ZoneName="Den"/><ZoneGroupMember UUID="RINCON_R" ZoneName="Den"/><ZoneGroupMember UUID="RINCON_SUB" ZoneName="Den" Invisible="1"/></ZoneGroup></ZoneGroups>"#;
+    let groups = parse_zone_group_state(state).unwrap();
+    assert_eq!(groups[0].members.len(), 2);
+    assert_eq!(groups[0].rooms(), vec!["Den"]);
+    listener.stop().await;
+}
@@ -0,0 +1,114 @@
+//! Allow and deny lists for the videos search returns, so karaoke channels or
+//! 8D audio uploads never make it into a playlist however well they score.
+//!
+//! Deny rules reject every video they match. An allow list that is not empty
+//! rejects every video it does not match. Patterns are case-insensitive
+//! regular expressions.
+
+use crate::models::SearchResultSnippet;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoFilterConfig {
+    allow_channel_ids: Option<Vec<String>>,
+    deny_channel_ids: Option<Vec<String>>,
+    allow_channel_titles: Option<Vec<String>>,
+    deny_channel_titles: Option<Vec<String>>,
+    allow_titles: Option<Vec<String>>,
+    deny_titles: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VideoFilter {
+    allow_channel_ids: Vec<String>,
+    deny_channel_ids: Vec<String>,
+    allow_channel_titles: Vec<Regex>,
+    deny_channel_titles: Vec<Regex>,
+    allow_titles: Vec<Regex>,
+    deny_titles: Vec<Regex>,
+}
+
+fn compile(patterns: &Option<Vec<String>>, setting: &str) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .flatten()
+        .map(|pattern| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| format!("{} pattern {:?} is invalid: {}", setting, pattern, e))
+        })
+        .collect()
+}
+
+impl VideoFilter {
+    pub fn new(config: &VideoFilterConfig) -> Result<VideoFilter, String> {
+        Ok(VideoFilter {
+            allow_channel_ids: config.allow_channel_ids.clone().unwrap_or_default(),
+            deny_channel_ids: config.deny_channel_ids.clone().unwrap_or_default(),
+            allow_channel_titles: compile(&config.allow_channel_titles, "allowChannelTitles")?,
+            deny_channel_titles: compile(&config.deny_channel_titles, "denyChannelTitles")?,
+            allow_titles: compile(&config.allow_titles, "allowTitles")?,
+            deny_titles: compile(&config.deny_titles, "denyTitles")?,
+        })
+    }
+
+    /// The rule that rejects this video, or `None` if it may be chosen.
+    pub fn rejection(&self, snippet: &SearchResultSnippet) -> Option<String> {
+        if self.deny_channel_ids.contains(&snippet.channel_id) {
+            return Some(format!("channel id {} is denied", snippet.channel_id));
+        }
+        if let Some(pattern) = self.deny_channel_titles.iter().find(|p| p.is_match(&snippet.channel_title)) {
+            return Some(format!("channel title matches denied /{}/", pattern));
+        }
+        if let Some(pattern) = self.deny_titles.iter().find(|p| p.is_match(&snippet.title)) {
+            return Some(format!("title matches denied /{}/", pattern));
+        }
+        if !self.allow_channel_ids.is_empty() && !self.allow_channel_ids.contains(&snippet.channel_id) {
+            return Some(format!("channel id {} is not allowed", snippet.channel_id));
+        }
+        if !self.allow_channel_titles.is_empty()
+            && !self.allow_channel_titles.iter().any(|p| p.is_match(&snippet.channel_title))
+        {
+            return Some(String::from("channel title matches no allowed pattern"));
+        }
+        if !self.allow_titles.is_empty() && !self.allow_titles.iter().any(|p| p.is_match(&snippet.title)) {
+            return Some(String::from("title matches no allowed pattern"));
+        }
+        None
+    }
+}
+
+#[test]
+fn test_video_filter() {
+    let snippet = |title: &str, channel_id: &str, channel_title: &str| SearchResultSnippet {
+        title: title.to_string(),
+        channel_id: channel_id.to_string(),
+        channel_title: channel_title.to_string(),
+        ..Default::default()
+    };
+    let official = snippet("Ed Sheeran - Shape of You (Official Music Video)", "UCEdSheeran", "Ed Sheeran");
+    let karaoke = snippet("Shape of You - Ed Sheeran (Karaoke Version)", "UCSingKing", "Sing King");
+    let spatial = snippet("Ed Sheeran - Shape of You (8D AUDIO)", "UC8D", "8D Tunes");
+
+    let config: VideoFilterConfig = serde_json::from_value(serde_json::json!({
+        "denyChannelIds": ["UCSingKing"],
+        "denyTitles": [r"\b8d\b"],
+    }))
+    .unwrap();
+    let filter = VideoFilter::new(&config).unwrap();
+    assert_eq!(filter.rejection(&official), None);
+    assert!(filter.rejection(&karaoke).unwrap().contains("UCSingKing"));
+    assert!(filter.rejection(&spatial).unwrap().contains("8d"));
+
+    let config: VideoFilterConfig =
+        serde_json::from_value(serde_json::json!({ "allowChannelTitles": ["^ed sheeran$", "- topic$"] })).unwrap();
+    let filter = VideoFilter::new(&config).unwrap();
+    assert_eq!(filter.rejection(&official), None);
+    assert!(filter.rejection(&karaoke).is_some());
+
+    let config: VideoFilterConfig = serde_json::from_value(serde_json::json!({ "denyTitles": ["(unclosed"] })).unwrap();
+    assert!(VideoFilter::new(&config).unwrap_err().contains("denyTitles"));
+}